@@ -0,0 +1,155 @@
+/*
+ * The runtime that objects built with `kalos build` link against: the host functions of
+ * `default_runtime`, and the traps and counters that generated code uses, see src/runtime.rs.
+ * Build an executable with
+ *
+ *     kalos build script.kls -o script.o
+ *     cc script.o runtime/kalos_runtime.c -o script
+ *
+ * A trap prints its error and exits with status 101, as `kalos run` does.
+ */
+#define _POSIX_C_SOURCE 200809L
+
+#include <ctype.h>
+#include <errno.h>
+#include <inttypes.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+/* the symbols of generated code have dots in them, which C identifiers cannot */
+#define KALOS(name) __asm__("kalos." name)
+
+/* see `JITExecutionEngine::set_fuel`, `set_max_depth` and `interrupt_handle` */
+int64_t kalos_fuel KALOS("fuel") = INT64_MAX;
+int64_t kalos_depth KALOS("depth") = 10000;
+uint8_t kalos_interrupt KALOS("interrupt") = 0;
+
+static void fail(const char *code, const char *message) {
+    fflush(stdout);
+    fprintf(stderr, "%s: %s\n", code, message);
+    exit(101);
+}
+
+void kalos_trap(const char *message) KALOS("trap");
+void kalos_trap(const char *message) {
+    fail("RuntimeError", message);
+}
+
+/* traps exit right away, so nothing has trapped by the time generated code asks */
+bool kalos_trapped(void) KALOS("trapped");
+bool kalos_trapped(void) {
+    return false;
+}
+
+void kalos_out_of_fuel(void) KALOS("out_of_fuel");
+void kalos_out_of_fuel(void) {
+    fail("OutOfFuel", "the script used up its fuel");
+}
+
+void kalos_interrupted(uint8_t *flag) KALOS("interrupted");
+void kalos_interrupted(uint8_t *flag) {
+    *flag = 0;
+    fail("Interrupted", "the script was interrupted");
+}
+
+void kalos_stack_overflow(void) KALOS("stack_overflow");
+void kalos_stack_overflow(void) {
+    fail("StackOverflow", "the script recursed too deep");
+}
+
+void print_str(const char *s) {
+    fputs(s, stdout);
+}
+
+void print_int(int64_t n) {
+    printf("%" PRId64, n);
+}
+
+void print_bool(bool b) {
+    fputs(b ? "true" : "false", stdout);
+}
+
+/* the current line of stdin, of which everything before `pos` has been consumed, read as
+ * `Input` in src/runtime.rs does */
+static char *buf;
+static size_t cap;
+static size_t len;
+static size_t pos;
+
+static bool next_line(void) {
+    ssize_t n = getline(&buf, &cap, stdin);
+    len = n > 0 ? (size_t) n : 0;
+    pos = 0;
+    return n > 0;
+}
+
+bool has_input(void) {
+    for (;;) {
+        while (pos < len && isspace((unsigned char) buf[pos])) {
+            pos++;
+        }
+        if (pos < len) {
+            return true;
+        }
+        if (!next_line()) {
+            return false;
+        }
+    }
+}
+
+static size_t token_len(void) {
+    size_t n = 0;
+    while (pos + n < len && !isspace((unsigned char) buf[pos + n])) {
+        n++;
+    }
+    return n;
+}
+
+/* parse the next token, which `has_input` has found, as an integer */
+static bool parse_token(int64_t *value) {
+    char *end;
+    errno = 0;
+    *value = strtoimax(buf + pos, &end, 10);
+    return errno == 0 && end != buf + pos && end == buf + pos + token_len();
+}
+
+bool has_int(void) {
+    int64_t value;
+    return has_input() && parse_token(&value);
+}
+
+int64_t read_int(void) {
+    int64_t value;
+    if (!has_input()) {
+        fail("RuntimeError", "no integer left to read");
+    }
+    if (!parse_token(&value)) {
+        char message[128];
+        snprintf(message, sizeof message, "expected an integer, found `%.*s`",
+                 (int) token_len(), buf + pos);
+        fail("RuntimeError", message);
+    }
+    pos += token_len();
+    return value;
+}
+
+bool has_line(void) {
+    return pos < len || next_line();
+}
+
+/* the line is never freed: scripts may keep it as long as they run */
+const char *read_line(void) {
+    if (!has_line()) {
+        fail("RuntimeError", "no line left to read");
+    }
+    size_t n = len - pos;
+    while (n > 0 && (buf[pos + n - 1] == '\n' || buf[pos + n - 1] == '\r')) {
+        n--;
+    }
+    char *line = strndup(buf + pos, n);
+    pos = len;
+    return line;
+}
@@ -1,6 +1,7 @@
 use std::error::Error;
 use std::fmt::{Display, Formatter};
 
+
 #[derive(Hash, PartialEq, Eq, Copy, Clone, Debug)]
 pub enum KalosBuiltin {
     Add,
@@ -17,17 +18,77 @@ pub enum KalosBuiltin {
     NotEqual,
 }
 
-#[derive(Clone, Debug)]
-pub enum KalosExpr {
+impl KalosBuiltin {
+    /// How the operator is written in the source.
+    pub fn operator(self) -> &'static str {
+        use KalosBuiltin::*;
+        match self {
+            Add => "+",
+            Subtract => "-",
+            Multiply => "*",
+            Divide => "/",
+            Modulo => "%",
+            Power => "**",
+            LessThan => "<",
+            LessEqual => "<=",
+            Equal => "==",
+            GreaterEqual => ">=",
+            GreaterThan => ">",
+            NotEqual => "!=",
+        }
+    }
+}
+
+/// A region of the source text: byte offsets plus the (1-based) line and column where it starts.
+#[derive(Hash, PartialEq, Eq, Copy, Clone, Debug, Default)]
+pub struct KalosSpan {
+    pub start: usize,
+    pub end: usize,
+    pub line: u32,
+    pub column: u32,
+}
+
+impl KalosSpan {
+    /// The smallest span covering both `self` and `other`, assuming `other` does not start
+    /// before `self`.
+    pub fn to(self, other: Self) -> Self {
+        Self { end: other.end, ..self }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum KalosExprKind {
     UnitLiteral,
     BoolLiteral(bool),
     IntLiteral(i64),
     StringLiteral(String),
-    Call { func: Box<Self>, args: Vec<Self> },
-    Builtin { builtin: KalosBuiltin, args: Vec<Self> },
+    Call { func: Box<KalosExpr>, args: Vec<KalosExpr> },
+    Builtin { builtin: KalosBuiltin, args: Vec<KalosExpr> },
     Identifier(String),
 }
 
+/// A piece of a format string: either text to be printed as is, or a `{}` to be replaced by an
+/// argument.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum KalosFormatPiece {
+    Text(String),
+    Placeholder,
+}
+
+#[derive(Clone, Debug)]
+pub struct KalosExpr {
+    pub kind: KalosExprKind,
+    pub span: KalosSpan,
+}
+
+// nodes are compared regardless of where they are, so that programs differing only in layout are
+// equal
+impl PartialEq for KalosExpr {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind == other.kind
+    }
+}
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum KalosType {
     Auto,
@@ -39,12 +100,17 @@ pub enum KalosType {
     Text,
 
     Function { signature: KalosSignature },
+
+    /// The type of what failed to check, which goes with anything so that one error does not
+    /// cause others.
+    Error,
 }
 
 impl Display for KalosType {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             KalosType::Auto => write!(f, "_"),
+            KalosType::Error => write!(f, "{{error}}"),
             KalosType::Unit => write!(f, "()"),
             KalosType::Bool => write!(f, "bool"),
             KalosType::Integer { signed, width } =>
@@ -71,8 +137,10 @@ impl Display for KalosType {
 impl KalosType {
     pub fn try_unify<'a>(&'a self, other: &'a Self) -> Result<&'a Self, KalosError> {
         use KalosType::*;
-        if let Auto = self {
+        if let Auto | Error = self {
             Ok(other)
+        } else if let Error = other {
+            Ok(self)
         } else if *self == *other {
             Ok(other)
         } else {
@@ -81,17 +149,29 @@ impl KalosType {
     }
 }
 
-#[derive(Clone, Debug)]
-pub enum KalosStmt {
-    Compound(Vec<Self>),
+#[derive(Clone, Debug, PartialEq)]
+pub enum KalosStmtKind {
+    Compound(Vec<KalosStmt>),
     Assignment { lhs: KalosExpr, rhs: KalosExpr },
     Var { name: String, ty: KalosType, initializer: Option<KalosExpr> },
     Return(KalosExpr),
-    If { cond: KalosExpr, then_part: Box<Self>, else_part: Option<Box<Self>> },
-    While { cond: KalosExpr, body: Box<Self> },
+    If { cond: KalosExpr, then_part: Box<KalosStmt>, else_part: Option<Box<KalosStmt>> },
+    While { cond: KalosExpr, body: Box<KalosStmt> },
     Expression(KalosExpr),
 }
 
+#[derive(Clone, Debug)]
+pub struct KalosStmt {
+    pub kind: KalosStmtKind,
+    pub span: KalosSpan,
+}
+
+impl PartialEq for KalosStmt {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind == other.kind
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct KalosSignature {
     pub params: Vec<(String, KalosType)>,
@@ -107,33 +187,271 @@ impl PartialEq for KalosSignature {
 }
 impl Eq for KalosSignature {}
 
+impl KalosSignature {
+    /// Whether functions of the two signatures can be used in place of each other, that is,
+    /// whether the signatures agree on everything but the names of parameters.
+    pub fn is_compatible(&self, other: &Self) -> bool {
+        self.params.len() == other.params.len() &&
+            self.params.iter().zip(&other.params).all(|((_, x), (_, y))| x == y) &&
+            *self.return_type == *other.return_type && self.variadic == other.variadic
+    }
+}
+
+#[derive(Clone, Debug)]
 pub enum KalosToplevel {
-    Def { name: String, signature: KalosSignature, body: Option<KalosStmt> },
+    /// `name_span` is the span of its name, and `param_spans` has the span of each parameter of
+    /// `signature`.
+    Def {
+        name: String,
+        signature: KalosSignature,
+        body: Option<KalosStmt>,
+        span: KalosSpan,
+        name_span: KalosSpan,
+        param_spans: Vec<KalosSpan>,
+    },
+}
+
+impl KalosToplevel {
+    pub fn span(&self) -> KalosSpan {
+        match self {
+            KalosToplevel::Def { span, .. } => *span,
+        }
+    }
+}
+
+impl PartialEq for KalosToplevel {
+    fn eq(&self, other: &Self) -> bool {
+        let KalosToplevel::Def { name, signature, body, .. } = self;
+        let KalosToplevel::Def { name: other_name, signature: other_signature, body: other_body, .. } = other;
+        name == other_name && signature == other_signature && body == other_body
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct KalosAttribute {
+    pub name: String,
+    pub args: Vec<String>,
+    pub span: KalosSpan,
 }
 
+impl PartialEq for KalosAttribute {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name && self.args == other.args
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct KalosProgram {
+    pub attributes: Vec<KalosAttribute>,
     pub program: Vec<KalosToplevel>,
 }
 
-#[derive(Debug)]
+impl KalosProgram {
+    /// Whether the host functions are available without `extern` declarations, which scripts can
+    /// opt out of with `#![no_prelude]`.
+    pub fn uses_prelude(&self) -> bool {
+        !self.attributes.iter().any(|a| a.name == "no_prelude")
+    }
+}
+
+/// Something legal but suspicious in a script, which it is warned about unless allowed.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum KalosLint {
+    UnusedVariables,
+    UnusedFunctions,
+    UnusedParameters,
+    Shadowing,
+    UnreachableCode,
+    ConstantConditions,
+}
+
+impl KalosLint {
+    pub const ALL: &'static [KalosLint] = &[
+        KalosLint::UnusedVariables,
+        KalosLint::UnusedFunctions,
+        KalosLint::UnusedParameters,
+        KalosLint::Shadowing,
+        KalosLint::UnreachableCode,
+        KalosLint::ConstantConditions,
+    ];
+
+    /// How the lint is named in attributes and on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            KalosLint::UnusedVariables => "unused_variables",
+            KalosLint::UnusedFunctions => "unused_functions",
+            KalosLint::UnusedParameters => "unused_parameters",
+            KalosLint::Shadowing => "shadowing",
+            KalosLint::UnreachableCode => "unreachable_code",
+            KalosLint::ConstantConditions => "constant_conditions",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        KalosLint::ALL.iter().copied().find(|lint| lint.name() == name)
+    }
+}
+
+#[derive(Clone, Debug)]
 pub enum KalosError {
-    NameError,
+    SyntaxError(String),
+    NameError { name: String, suggestions: Vec<String> },
     TypeError { expect: KalosType, found: KalosType },
+    /// A variable declared with neither a type nor a value to infer it from.
+    InferenceError(String),
     LvalueError,
+    /// Reading a variable that may not have been assigned a value yet.
+    UninitializedError(String),
     ArgError,
+    ReturnError,
+    ExternError { name: String, expect: KalosType, found: KalosType },
+    /// An attribute of the script that means nothing.
+    AttributeError(String),
+    FormatError(String),
+    RuntimeError(String),
+    OutOfFuel,
+    Interrupted,
+    StackOverflow,
+    CapabilityError(String),
+    LinkError(String),
+    /// Redefining a function of a running engine with another signature.
+    RedefinitionError(String),
+    MainError(String),
+    /// Reading a script or writing what it compiles to failed.
+    IoError(String),
+    /// LLVM cannot generate code for the host machine.
+    TargetError(String),
+    /// What a lint found, see `lint_program`.
+    Lint { lint: KalosLint, message: String },
+    /// Several errors at once, such as all the syntax errors of a script.
+    Diagnostics(Vec<KalosDiagnostic>),
+}
+
+impl KalosError {
+    /// The error as found at `span`, as diagnostics of its own.
+    pub fn at(self, span: KalosSpan) -> KalosError {
+        KalosError::Diagnostics(vec![KalosDiagnostic {
+            error: self,
+            span: Some(span),
+            notes: Vec::new(),
+            severity: KalosSeverity::Error,
+        }])
+    }
+
+    /// Whether the error was raised by a running script, see `catch_trap`.
+    pub fn is_trap(&self) -> bool {
+        use KalosError::*;
+        matches!(self, RuntimeError(_) | OutOfFuel | Interrupted | StackOverflow)
+    }
+
+    /// What kind of error this is, such as `TypeError`.
+    pub fn code(&self) -> &'static str {
+        use KalosError::*;
+        match self {
+            SyntaxError(_) => "SyntaxError",
+            NameError { .. } => "NameError",
+            TypeError { .. } => "TypeError",
+            InferenceError(_) => "InferenceError",
+            LvalueError => "LvalueError",
+            UninitializedError(_) => "UninitializedError",
+            ArgError => "ArgError",
+            ReturnError => "ReturnError",
+            ExternError { .. } => "ExternError",
+            AttributeError(_) => "AttributeError",
+            FormatError(_) => "FormatError",
+            RuntimeError(_) => "RuntimeError",
+            OutOfFuel => "OutOfFuel",
+            Interrupted => "Interrupted",
+            StackOverflow => "StackOverflow",
+            CapabilityError(_) => "CapabilityError",
+            LinkError(_) => "LinkError",
+            RedefinitionError(_) => "RedefinitionError",
+            MainError(_) => "MainError",
+            IoError(_) => "IoError",
+            TargetError(_) => "TargetError",
+            Lint { lint, .. } => lint.name(),
+            Diagnostics(_) => "Diagnostics",
+        }
+    }
+
+    /// What went wrong, without the kind of error.
+    pub fn message(&self) -> String {
+        use KalosError::*;
+        match self {
+            SyntaxError(msg) | AttributeError(msg) | FormatError(msg) | RuntimeError(msg) |
+            CapabilityError(msg) | MainError(msg) | IoError(msg) | TargetError(msg) |
+            Lint { message: msg, .. } => msg.to_owned(),
+            NameError { name, suggestions } => match suggestions.split_last() {
+                Some((last, [])) => format!("{} is not defined, did you mean {}?", name, last),
+                Some((last, rest)) =>
+                    format!("{} is not defined, did you mean {} or {}?", name, rest.join(", "), last),
+                None => format!("{} is not defined", name),
+            },
+            TypeError { expect, found } => format!("expect {} found {}", expect, found),
+            InferenceError(name) => format!("the type of {} is neither given nor inferred", name),
+            LvalueError => "only variables can be assigned to".to_owned(),
+            UninitializedError(name) => format!("{} may be read before it is assigned a value", name),
+            ArgError => "wrong number of arguments".to_owned(),
+            ReturnError => "return outside of a function".to_owned(),
+            ExternError { name, expect, found } =>
+                format!("{} is provided as {} but declared as {}", name, expect, found),
+            OutOfFuel => "the script used up its fuel".to_owned(),
+            Interrupted => "the script was interrupted".to_owned(),
+            StackOverflow => "the script recursed too deep".to_owned(),
+            LinkError(name) => format!("{} is not defined", name),
+            RedefinitionError(name) => format!("{} is already defined with another signature", name),
+            Diagnostics(diagnostics) => diagnostics.iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join("\n"),
+        }
+    }
 }
 
 impl Display for KalosError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        use KalosError::*;
         match self {
-            NameError => write!(f, "NameError"),
-            TypeError { expect, found } =>
-                write!(f, "TypeError: expect {} found {}", expect, found),
-            LvalueError => write!(f, "LvalueError"),
-            ArgError => write!(f, "ArgError"),
+            KalosError::Diagnostics(diagnostics) => {
+                for (i, diagnostic) in diagnostics.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{}", diagnostic)?;
+                }
+                Ok(())
+            }
+            _ => write!(f, "{}: {}", self.code(), self.message()),
         }
     }
 }
 
 impl Error for KalosError {}
+
+/// Whether a diagnostic keeps the script from running.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum KalosSeverity {
+    Error,
+    Warning,
+}
+
+/// An error found in a script, or a warning about it, and where it is if known.
+#[derive(Clone, Debug)]
+pub struct KalosDiagnostic {
+    pub error: KalosError,
+    pub span: Option<KalosSpan>,
+    /// Other places that have to do with the error, and what they have to do with it.
+    pub notes: Vec<(KalosSpan, String)>,
+    pub severity: KalosSeverity,
+}
+
+impl Display for KalosDiagnostic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if let Some(span) = self.span {
+            write!(f, "{}:{}: ", span.line, span.column)?;
+        }
+        if self.severity == KalosSeverity::Warning {
+            write!(f, "warning: ")?;
+        }
+        write!(f, "{}", self.error)
+    }
+}
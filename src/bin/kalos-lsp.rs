@@ -0,0 +1,420 @@
+//! A language server for Kalos scripts, speaking the Language Server Protocol over stdin and
+//! stdout.  It reports the errors and warnings of open scripts, shows the types of names on
+//! hover, jumps to their definitions and completes the names in scope.
+
+use std::collections::HashMap;
+use std::io::{BufRead, stdin, stdout, Stdout, Write};
+use std::process::exit;
+
+use kalos::{analyze, default_runtime, Json, KalosAnalysis, KalosOptions, KalosRuntime, KalosSeverity, KalosSpan, KalosType};
+
+// see the specification of the protocol
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const SEVERITY_ERROR: i64 = 1;
+const SEVERITY_WARNING: i64 = 2;
+const COMPLETION_FUNCTION: i64 = 3;
+const COMPLETION_VARIABLE: i64 = 6;
+const SYNC_FULL: i64 = 1;
+
+/// Read a message, `Err` with what is wrong with it if it cannot be parsed, or `None` once the
+/// client has gone away.
+fn read_message(input: &mut impl BufRead) -> Option<Result<Json, String>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header).ok()? == 0 {
+            return None;
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+    // without a length there is no telling where the content ends, so whatever follows is read
+    // as the headers of the next message
+    let length = match length {
+        Some(length) => length,
+        None => return Some(Err("missing or invalid Content-Length".to_owned())),
+    };
+    let mut content = vec![0; length];
+    input.read_exact(&mut content).ok()?;
+    let message = String::from_utf8(content).ok().as_deref().and_then(Json::parse);
+    Some(message.ok_or_else(|| "the content is not valid JSON".to_owned()))
+}
+
+fn write_message(output: &mut impl Write, message: Json) {
+    let content = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", content.len(), content)
+        .and_then(|_| output.flush())
+        .expect("some write thing failed");
+}
+
+fn error_response(id: Json, code: i64, message: String) -> Json {
+    Json::object(vec![
+        ("jsonrpc", Json::from("2.0")),
+        ("id", id),
+        ("error", Json::object(vec![("code", Json::Int(code)), ("message", Json::from(message))])),
+    ])
+}
+
+/// The byte offset of an LSP position, which counts UTF-16 code units within its line.
+fn position_to_offset(text: &str, position: &Json) -> Option<usize> {
+    let line = position.get("line")?.as_i64()? as usize;
+    let character = position.get("character")?.as_i64()? as usize;
+    let start = if line == 0 {
+        0
+    } else {
+        text.match_indices('\n').nth(line - 1)?.0 + 1
+    };
+    let mut units = 0;
+    for (i, c) in text[start..].char_indices() {
+        if units >= character || c == '\n' {
+            return Some(start + i);
+        }
+        units += c.len_utf16();
+    }
+    Some(text.len())
+}
+
+fn offset_to_position(text: &str, offset: usize) -> Json {
+    let offset = offset.min(text.len());
+    let start = text[..offset].rfind('\n').map_or(0, |i| i + 1);
+    let line = text[..start].matches('\n').count();
+    let character: usize = text[start..offset].chars().map(char::len_utf16).sum();
+    Json::object(vec![("line", Json::from(line)), ("character", Json::from(character))])
+}
+
+fn range(text: &str, span: Option<KalosSpan>) -> Json {
+    let (start, end) = span.map_or((0, 0), |span| (span.start, span.end));
+    Json::object(vec![
+        ("start", offset_to_position(text, start)),
+        ("end", offset_to_position(text, end)),
+    ])
+}
+
+struct Server<W: Write> {
+    output: W,
+    runtime: KalosRuntime,
+    options: KalosOptions,
+    // the text of the open documents by URI
+    documents: HashMap<String, String>,
+    shut_down: bool,
+}
+
+impl<W: Write> Server<W> {
+    fn new(output: W) -> Self {
+        Server {
+            output,
+            runtime: default_runtime(),
+            options: KalosOptions::default(),
+            documents: HashMap::new(),
+            shut_down: false,
+        }
+    }
+
+    fn analyze(&self, uri: &str) -> Option<(&str, KalosAnalysis)> {
+        let text = self.documents.get(uri)?;
+        Some((text, analyze(text, &self.runtime, &self.options)))
+    }
+
+    fn publish_diagnostics(&mut self, uri: &str) {
+        let diagnostics = match self.analyze(uri) {
+            Some((text, analysis)) => analysis.diagnostics.iter().map(|diagnostic| {
+                let related = diagnostic.notes.iter().map(|(span, note)| Json::object(vec![
+                    ("location", Json::object(vec![
+                        ("uri", Json::from(uri)),
+                        ("range", range(text, Some(*span))),
+                    ])),
+                    ("message", Json::from(note.as_str())),
+                ])).collect();
+                Json::object(vec![
+                    ("range", range(text, diagnostic.span)),
+                    ("severity", Json::Int(match diagnostic.severity {
+                        KalosSeverity::Error => SEVERITY_ERROR,
+                        KalosSeverity::Warning => SEVERITY_WARNING,
+                    })),
+                    ("code", Json::from(diagnostic.error.code())),
+                    ("source", Json::from("kalos")),
+                    ("message", Json::from(diagnostic.error.message())),
+                    ("relatedInformation", Json::Array(related)),
+                ])
+            }).collect(),
+            None => Vec::new(),
+        };
+        write_message(&mut self.output, Json::object(vec![
+            ("jsonrpc", Json::from("2.0")),
+            ("method", Json::from("textDocument/publishDiagnostics")),
+            ("params", Json::object(vec![
+                ("uri", Json::from(uri)),
+                ("diagnostics", Json::Array(diagnostics)),
+            ])),
+        ]));
+    }
+
+    /// The document and the byte offset the request is about.
+    fn document_position<'a>(&self, params: &'a Json) -> Option<(&'a str, usize)> {
+        let uri = params.get("textDocument")?.get("uri")?.as_str()?;
+        let offset = position_to_offset(self.documents.get(uri)?, params.get("position")?)?;
+        Some((uri, offset))
+    }
+
+    fn hover(&self, params: &Json) -> Option<Json> {
+        let (uri, offset) = self.document_position(params)?;
+        let (text, analysis) = self.analyze(uri)?;
+        let reference = analysis.index.reference_at(offset)?;
+        Some(Json::object(vec![
+            ("contents", Json::object(vec![
+                ("kind", Json::from("plaintext")),
+                ("value", Json::from(format!("{}: {}", reference.name, reference.ty))),
+            ])),
+            ("range", range(text, Some(reference.span))),
+        ]))
+    }
+
+    fn definition(&self, params: &Json) -> Option<Json> {
+        let (uri, offset) = self.document_position(params)?;
+        let (text, analysis) = self.analyze(uri)?;
+        let definition = analysis.index.reference_at(offset)?.definition?;
+        Some(Json::object(vec![
+            ("uri", Json::from(uri)),
+            ("range", range(text, Some(definition))),
+        ]))
+    }
+
+    fn completion(&self, params: &Json) -> Option<Json> {
+        let (uri, offset) = self.document_position(params)?;
+        let (_, analysis) = self.analyze(uri)?;
+        let items = analysis.index.bindings_at(offset).into_iter().map(|binding| {
+            let kind = match binding.ty {
+                KalosType::Function { .. } => COMPLETION_FUNCTION,
+                _ => COMPLETION_VARIABLE,
+            };
+            Json::object(vec![
+                ("label", Json::from(binding.name.as_str())),
+                ("kind", Json::Int(kind)),
+                ("detail", Json::from(binding.ty.to_string())),
+            ])
+        }).collect();
+        Some(Json::Array(items))
+    }
+
+    /// Answer a request, or `Err` with an error code.
+    fn request(&mut self, method: &str, params: &Json) -> Result<Json, i64> {
+        match method {
+            "initialize" => Ok(Json::object(vec![
+                ("capabilities", Json::object(vec![
+                    ("textDocumentSync", Json::Int(SYNC_FULL)),
+                    ("hoverProvider", Json::Bool(true)),
+                    ("definitionProvider", Json::Bool(true)),
+                    ("completionProvider", Json::object(vec![])),
+                ])),
+                ("serverInfo", Json::object(vec![("name", Json::from("kalos-lsp"))])),
+            ])),
+            "shutdown" => {
+                self.shut_down = true;
+                Ok(Json::Null)
+            }
+            "textDocument/hover" => Ok(self.hover(params).into()),
+            "textDocument/definition" => Ok(self.definition(params).into()),
+            "textDocument/completion" => Ok(self.completion(params).into()),
+            _ => Err(METHOD_NOT_FOUND),
+        }
+    }
+
+    fn notification(&mut self, method: &str, params: &Json) -> Option<()> {
+        match method {
+            "textDocument/didOpen" => {
+                let document = params.get("textDocument")?;
+                let uri = document.get("uri")?.as_str()?;
+                self.documents.insert(uri.to_owned(), document.get("text")?.as_str()?.to_owned());
+                self.publish_diagnostics(uri);
+            }
+            "textDocument/didChange" => {
+                let uri = params.get("textDocument")?.get("uri")?.as_str()?;
+                // the whole text, as asked for by `SYNC_FULL`
+                let text = match params.get("contentChanges")? {
+                    Json::Array(changes) => changes.last()?.get("text")?.as_str()?,
+                    _ => return None,
+                };
+                self.documents.insert(uri.to_owned(), text.to_owned());
+                self.publish_diagnostics(uri);
+            }
+            "textDocument/didClose" => {
+                let uri = params.get("textDocument")?.get("uri")?.as_str()?;
+                self.documents.remove(uri);
+                self.publish_diagnostics(uri);
+            }
+            "exit" => exit(if self.shut_down { 0 } else { 1 }),
+            _ => {}
+        }
+        Some(())
+    }
+
+    fn handle(&mut self, message: &Json) {
+        // responses to requests of ours, which there are none of
+        let method = match message.get("method").and_then(Json::as_str) {
+            Some(method) => method,
+            None => return,
+        };
+        let params = message.get("params").unwrap_or(&Json::Null);
+        let id = match message.get("id") {
+            Some(id) => id.to_owned(),
+            None => {
+                // malformed notifications are ignored, as there is no one to tell
+                self.notification(method, params);
+                return;
+            }
+        };
+        let response = match self.request(method, params) {
+            Ok(result) =>
+                Json::object(vec![("jsonrpc", Json::from("2.0")), ("id", id), ("result", result)]),
+            Err(code) => error_response(id, code, format!("unknown method {}", method)),
+        };
+        write_message(&mut self.output, response);
+    }
+
+    /// Serve the messages of `input` until the client goes away.
+    fn serve(&mut self, input: &mut impl BufRead) {
+        while let Some(message) = read_message(input) {
+            match message {
+                Ok(message) => self.handle(&message),
+                // the id of the request is in what could not be parsed
+                Err(error) => {
+                    let response = error_response(Json::Null, PARSE_ERROR, error);
+                    write_message(&mut self.output, response);
+                }
+            }
+        }
+    }
+}
+
+fn main() {
+    let mut server: Server<Stdout> = Server::new(stdout());
+    let stdin = stdin();
+    server.serve(&mut stdin.lock());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn frame(content: &str) -> String {
+        format!("Content-Length: {}\r\n\r\n{}", content.len(), content)
+    }
+
+    /// The messages the server has written since the last call.
+    fn responses(server: &mut Server<Vec<u8>>) -> Vec<Json> {
+        let output = std::mem::take(&mut server.output);
+        let mut output = Cursor::new(output);
+        std::iter::from_fn(|| read_message(&mut output)).map(Result::unwrap).collect()
+    }
+
+    fn request(server: &mut Server<Vec<u8>>, method: &str, params: Json) -> Json {
+        server.handle(&Json::object(vec![
+            ("jsonrpc", Json::from("2.0")),
+            ("id", Json::Int(1)),
+            ("method", Json::from(method)),
+            ("params", params),
+        ]));
+        let mut responses = responses(server);
+        assert_eq!(responses.len(), 1);
+        responses.pop().unwrap().get("result").unwrap().to_owned()
+    }
+
+    fn at(uri: &str, line: usize, character: usize) -> Json {
+        Json::object(vec![
+            ("textDocument", Json::object(vec![("uri", Json::from(uri))])),
+            ("position", Json::object(vec![
+                ("line", Json::from(line)),
+                ("character", Json::from(character)),
+            ])),
+        ])
+    }
+
+    #[test]
+    fn test_read_message() {
+        let input = [
+            "Content-Type: text/plain\r\n\r\n".to_owned(),
+            frame("{\"jsonrpc\": \"2.0\", \"id\": 1,"),
+            // a response of the client, which is not answered
+            frame("{\"jsonrpc\":\"2.0\",\"id\":3,\"result\":null}"),
+            frame("{\"jsonrpc\":\"2.0\",\"id\":2,\"method\":\"shutdown\"}"),
+        ].concat();
+        let mut server = Server::new(Vec::new());
+        server.serve(&mut Cursor::new(input));
+        let responses = responses(&mut server);
+        assert_eq!(responses.len(), 3);
+        for response in &responses[..2] {
+            assert_eq!(response.get("id"), Some(&Json::Null));
+            assert_eq!(response.get("error").unwrap().get("code"), Some(&Json::Int(PARSE_ERROR)));
+        }
+        assert_eq!(responses[2].get("id"), Some(&Json::Int(2)));
+        assert_eq!(responses[2].get("result"), Some(&Json::Null));
+        assert!(server.shut_down);
+    }
+
+    #[test]
+    fn test_positions() {
+        // the emoji takes two UTF-16 code units and four bytes
+        let text = "a\u{1f600}b\nc";
+        let position = |line: usize, character: usize| Json::object(vec![
+            ("line", Json::from(line)),
+            ("character", Json::from(character)),
+        ]);
+        assert_eq!(position_to_offset(text, &position(0, 0)), Some(0));
+        assert_eq!(position_to_offset(text, &position(0, 3)), Some(5));
+        assert_eq!(position_to_offset(text, &position(0, 99)), Some(6));
+        assert_eq!(position_to_offset(text, &position(1, 1)), Some(8));
+        assert_eq!(position_to_offset(text, &position(2, 0)), None);
+        assert_eq!(offset_to_position(text, 5), position(0, 3));
+        assert_eq!(offset_to_position(text, 7), position(1, 0));
+        assert_eq!(offset_to_position(text, 99), position(1, 1));
+    }
+
+    #[test]
+    fn test_handle() {
+        let mut server = Server::new(Vec::new());
+        let result = request(&mut server, "initialize", Json::object(vec![]));
+        let capabilities = result.get("capabilities").unwrap();
+        assert_eq!(capabilities.get("hoverProvider"), Some(&Json::Bool(true)));
+
+        let uri = "file:///twice.kls";
+        let text = "def twice(n: int) -> int {\n\treturn n + n;\n}\n\ndef main() {\n\treturn;\n}\n";
+        server.handle(&Json::object(vec![
+            ("jsonrpc", Json::from("2.0")),
+            ("method", Json::from("textDocument/didOpen")),
+            ("params", Json::object(vec![("textDocument", Json::object(vec![
+                ("uri", Json::from(uri)),
+                ("text", Json::from(text)),
+            ]))])),
+        ]));
+        let notifications = responses(&mut server);
+        assert_eq!(notifications.len(), 1);
+        let params = notifications[0].get("params").unwrap();
+        assert_eq!(params.get("uri"), Some(&Json::from(uri)));
+        let diagnostics = params.get("diagnostics").unwrap();
+        // `twice` is never used
+        assert!(matches!(diagnostics, Json::Array(d) if d.len() == 1));
+
+        let hover = request(&mut server, "textDocument/hover", at(uri, 1, 9));
+        let contents = hover.get("contents").unwrap();
+        assert_eq!(contents.get("value"), Some(&Json::from("n: i64")));
+        let definition = request(&mut server, "textDocument/definition", at(uri, 1, 9));
+        let start = definition.get("range").unwrap().get("start").unwrap();
+        assert_eq!(start.get("character"), Some(&Json::Int(10)));
+        let completion = request(&mut server, "textDocument/completion", at(uri, 1, 9));
+        let labels: Vec<&str> = match &completion {
+            Json::Array(items) =>
+                items.iter().map(|item| item.get("label").unwrap().as_str().unwrap()).collect(),
+            _ => panic!("expect a list of completions"),
+        };
+        assert!(labels.contains(&"n") && labels.contains(&"twice") && labels.contains(&"main"));
+        assert_eq!(request(&mut server, "textDocument/hover", at(uri, 5, 0)), Json::Null);
+    }
+}
@@ -1,17 +1,24 @@
-use std::collections::HashMap;
-use std::convert::TryInto;
+use std::collections::{HashMap, HashSet};
+use std::convert::{TryFrom, TryInto};
+use std::path::Path;
 
+use inkwell::attributes::{Attribute, AttributeLoc};
 use inkwell::basic_block::BasicBlock;
 use inkwell::builder::Builder;
 use inkwell::context::Context;
-use inkwell::IntPredicate;
+use inkwell::{AddressSpace, AtomicOrdering, IntPredicate};
 use inkwell::module::Module;
 use inkwell::passes::PassManager;
 use inkwell::types::{FunctionType, AnyTypeEnum, BasicTypeEnum, BasicType};
-use inkwell::values::{AnyValueEnum, BasicValueEnum, FunctionValue, PointerValue};
+use inkwell::values::{AnyValueEnum, BasicValue, BasicValueEnum, CallableValue, FunctionValue, IntValue, PointerValue};
 
-use crate::ast::{KalosBuiltin, KalosError, KalosExpr, KalosProgram, KalosSignature, KalosStmt, KalosToplevel, KalosType};
-use crate::env::Env;
+use crate::ast::{KalosBuiltin, KalosError, KalosExpr, KalosExprKind, KalosFormatPiece, KalosProgram, KalosSignature, KalosSpan, KalosStmt, KalosStmtKind, KalosToplevel, KalosType};
+use crate::debuginfo::DebugInfo;
+use crate::env::{Env, similar_names};
+use crate::execution::slot_symbol;
+use crate::parser::parse_format;
+use crate::runtime::{data_symbol, DEPTH_SYMBOL, FUEL_SYMBOL, INTERRUPT_SYMBOL, INTERRUPTED_SYMBOL, KalosPolicy, KalosRuntime, OUT_OF_FUEL_SYMBOL, STACK_OVERFLOW_SYMBOL, TRAP_SYMBOL, TRAPPED_SYMBOL};
+use crate::tyck::{builtin_function, explicit_arithmetic};
 
 pub struct LLVMCodeGen<'ctx, 'm> {
     context: &'ctx Context,
@@ -20,6 +27,11 @@ pub struct LLVMCodeGen<'ctx, 'm> {
     fpm: PassManager<FunctionValue<'ctx>>,
     env: Env<String, AnyValueEnum<'ctx>>,
     current_fn: Option<FunctionValue<'ctx>>,
+    debug_info: Option<DebugInfo<'ctx>>,
+    // host functions that are closures, see `KalosRuntime::register_closure`
+    closures: HashSet<String>,
+    // whether `+`, `-` and `*` trap on overflow rather than wrap around
+    checked_arithmetic: bool,
 }
 
 impl<'ctx, 'm> LLVMCodeGen<'ctx, 'm> {
@@ -44,8 +56,30 @@ impl<'ctx, 'm> LLVMCodeGen<'ctx, 'm> {
             fpm,
             env,
             current_fn: None,
+            debug_info: None,
+            closures: HashSet::new(),
+            checked_arithmetic: false,
         }
     }
+
+    pub fn with_runtime(context: &'ctx Context, module: &'m Module<'ctx>,
+                        runtime: &KalosRuntime) -> Self {
+        Self {
+            closures: runtime.closures().map(|(name, _)| name.to_owned()).collect(),
+            ..Self::new(context, module)
+        }
+    }
+
+    /// Emit DWARF debug info, attributing the generated code to the source file at `path`.
+    pub fn emit_debug_info(&mut self, path: &Path) {
+        self.debug_info = Some(DebugInfo::new(self.context, self.module, path));
+    }
+
+    /// Make `+`, `-` and `*` trap on overflow, as the `checked_*` builtins always do.  The
+    /// `wrapping_*` builtins still wrap around.
+    pub fn check_arithmetic(&mut self) {
+        self.checked_arithmetic = true;
+    }
 }
 
 impl<'ctx> LLVMCodeGen<'ctx, '_> {
@@ -56,22 +90,27 @@ impl<'ctx> LLVMCodeGen<'ctx, '_> {
     fn compile_type(&self, ty: &KalosType) -> AnyTypeEnum<'ctx> {
         use KalosType::*;
         match ty {
-            Auto => unreachable!(),
+            Auto | Error => unreachable!(),
             Unit => self.context.void_type().into(),
             Bool => self.context.bool_type().into(),
             Integer { width, signed } => self.context.i64_type().into(),
-            Text => unimplemented!(),
+            Text => self.context.i8_type().ptr_type(AddressSpace::Generic).into(),
             Function { signature } => self.compile_signature(signature).into(),
         }
     }
 
     fn compile_signature(&self, signature: &KalosSignature) -> FunctionType<'ctx> {
+        self.compile_fn_type(signature, &[])
+    }
+
+    fn compile_fn_type(&self, signature: &KalosSignature,
+                       leading_args: &[BasicTypeEnum<'ctx>]) -> FunctionType<'ctx> {
         // HACK: the void type is not a "BasicType" and requires special treatment
         // inkwell's IntType, FloatType, IntValue, FloatValue, etc. are inconvenient when code is
         // generated from an IR that has already been tycked.
         let return_type = self.compile_type(signature.return_type.as_ref());
-        let args: Vec<BasicTypeEnum> = signature.params.iter()
-            .map(|(_, ty)| self.compile_type(ty).try_into().unwrap()).collect();
+        let args: Vec<BasicTypeEnum> = leading_args.iter().copied().chain(signature.params.iter()
+            .map(|(_, ty)| self.compile_type(ty).try_into().unwrap())).collect();
         if return_type.is_void_type() {
             self.context.void_type().fn_type(&args, signature.variadic)
         } else {
@@ -80,26 +119,441 @@ impl<'ctx> LLVMCodeGen<'ctx, '_> {
         }
     }
 
+    /// The type of the function `name` declared with `signature`, which is provided by the host if
+    /// `external` is set.
+    fn compile_function_type(&self, name: &str, signature: &KalosSignature,
+                             external: bool) -> FunctionType<'ctx> {
+        if external && self.closures.contains(name) {
+            let data_type = self.context.i8_type().ptr_type(AddressSpace::Generic);
+            self.compile_fn_type(signature, &[data_type.into()])
+        } else {
+            self.compile_signature(signature)
+        }
+    }
+
+    /// Declare the host function `name` of type `fn_type`.  Rust expects `bool` arguments to be
+    /// zero-extended by the caller, which LLVM only does for parameters marked as such.
+    fn declare_host_function(&self, name: &str, fn_type: FunctionType<'ctx>) -> FunctionValue<'ctx> {
+        let func = self.module.add_function(name, fn_type, None);
+        let zeroext = Attribute::get_named_enum_kind_id("zeroext");
+        for (i, ty) in fn_type.get_param_types().into_iter().enumerate() {
+            if matches!(ty, BasicTypeEnum::IntType(ty) if ty.get_bit_width() == 1) {
+                func.add_attribute(AttributeLoc::Param(i as u32),
+                                   self.context.create_enum_attribute(zeroext, 0));
+            }
+        }
+        func
+    }
+
+    /// The state of the closure `name`, to be passed before its arguments.
+    fn load_closure_data(&self, name: &str) -> BasicValueEnum<'ctx> {
+        let symbol = data_symbol(name);
+        let global = self.module.get_global(&symbol).unwrap_or_else(|| {
+            let data_type = self.context.i8_type().ptr_type(AddressSpace::Generic);
+            self.module.add_global(data_type, None, &symbol)
+        });
+        self.builder.build_load(global.as_pointer_value(), "")
+    }
+
+    /// Call the host function `name` with a single argument.  It is declared as needed, as this is
+    /// for host functions that generated code relies on rather than the script itself.
+    fn call_host_function(&self, name: &str, arg_type: KalosType, arg: BasicValueEnum<'ctx>) {
+        let func = self.module.get_function(name).unwrap_or_else(|| {
+            let signature = KalosSignature {
+                params: vec![("_".to_owned(), arg_type)],
+                return_type: box KalosType::Unit,
+                variadic: false,
+            };
+            self.declare_host_function(name, self.compile_function_type(name, &signature, true))
+        });
+        let mut args = vec![arg];
+        if func.count_basic_blocks() == 0 && self.closures.contains(name) {
+            args.insert(0, self.load_closure_data(name));
+        }
+        self.builder.build_call(func, &args, "");
+        self.build_unwind_check();
+    }
+
+    /// `kalos.trap`, `kalos.trapped`, `kalos.out_of_fuel`, `kalos.interrupted` or
+    /// `kalos.stack_overflow` of the runtime, declared as needed.
+    fn trap_function(&self, name: &str) -> FunctionValue<'ctx> {
+        self.module.get_function(name).unwrap_or_else(|| {
+            let fn_type = match name {
+                TRAP_SYMBOL => {
+                    let message_type = self.context.i8_type().ptr_type(AddressSpace::Generic);
+                    self.context.void_type().fn_type(&[message_type.into()], false)
+                }
+                TRAPPED_SYMBOL => self.context.bool_type().fn_type(&[], false),
+                INTERRUPTED_SYMBOL => {
+                    let flag_type = self.context.i8_type().ptr_type(AddressSpace::Generic);
+                    self.context.void_type().fn_type(&[flag_type.into()], false)
+                }
+                _ => self.context.void_type().fn_type(&[], false),
+            };
+            self.module.add_function(name, fn_type, None)
+        })
+    }
+
+    /// The global `name` provided by the `JITExecutionEngine`, declared as needed.
+    fn engine_global(&self, name: &str, ty: BasicTypeEnum<'ctx>) -> PointerValue<'ctx> {
+        self.module.get_global(name)
+            .unwrap_or_else(|| self.module.add_global(ty, None, name))
+            .as_pointer_value()
+    }
+
+    /// Call the runtime function `name`, which raises a trap, and return if `cond` holds.
+    fn build_trap_call_if(&self, cond: IntValue<'ctx>, name: &str, args: &[BasicValueEnum<'ctx>]) {
+        let trap_block = self.new_block();
+        let cont_block = self.new_block();
+        self.builder.build_conditional_branch(cond, trap_block, cont_block);
+        self.builder.position_at_end(trap_block);
+        self.builder.build_call(self.trap_function(name), args, "");
+        self.build_unwind();
+        self.builder.position_at_end(cont_block);
+    }
+
+    /// Add `delta` to the counter `name` of the `JITExecutionEngine`, returning the new value.
+    fn build_count(&self, name: &str, delta: i64) -> IntValue<'ctx> {
+        let i64_type = self.context.i64_type();
+        let counter = self.engine_global(name, i64_type.into());
+        let value = self.builder.build_load(counter, "").into_int_value();
+        let value = self.builder.build_int_add(value, i64_type.const_int(delta as u64, true), "");
+        self.builder.build_store(counter, value);
+        value
+    }
+
+    /// Take one from the counter `name`, calling the runtime function `trap` and returning if it
+    /// drops below zero.
+    fn build_count_down(&self, name: &str, trap: &str) {
+        let left = self.build_count(name, -1);
+        let exhausted = self.builder.build_int_compare(
+            IntPredicate::SLT, left, self.context.i64_type().const_zero(), "");
+        self.build_trap_call_if(exhausted, trap, &[]);
+    }
+
+    /// Take a frame from what is left of the recursion depth limit, trapping if there is none.
+    /// Every function does this on entry, and gives the frame back with `build_return`.
+    fn build_enter_frame(&self) {
+        self.build_count_down(DEPTH_SYMBOL, STACK_OVERFLOW_SYMBOL);
+    }
+
+    fn build_return(&self, value: Option<&dyn BasicValue<'ctx>>) {
+        self.build_count(DEPTH_SYMBOL, 1);
+        self.builder.build_return(value);
+    }
+
+    /// Take a unit of fuel and check for interrupts, trapping if there is no fuel left or the
+    /// script has been interrupted.  This is done at every function entry and loop iteration, so
+    /// that scripts cannot run forever.
+    fn build_safepoint(&self) {
+        self.build_count_down(FUEL_SYMBOL, OUT_OF_FUEL_SYMBOL);
+
+        // the flag is an `AtomicBool` set by other threads, so it is loaded atomically, as
+        // `Ordering::Relaxed` would, which also keeps the load from being hoisted out of loops
+        let i8_type = self.context.i8_type();
+        let interrupt = self.engine_global(INTERRUPT_SYMBOL, i8_type.into());
+        let flag = self.builder.build_load(interrupt, "");
+        let load = flag.as_instruction_value().unwrap();
+        load.set_atomic_ordering(AtomicOrdering::Monotonic).unwrap();
+        load.set_alignment(1).unwrap();
+        let interrupted = self.builder.build_int_compare(
+            IntPredicate::NE, flag.into_int_value(), i8_type.const_zero(), "");
+        // which clears the flag, so that the interrupt stops this script only
+        self.build_trap_call_if(interrupted, INTERRUPTED_SYMBOL, &[interrupt.into()]);
+    }
+
+    /// Zero, false or null.
+    fn const_zero(&self, ty: BasicTypeEnum<'ctx>) -> BasicValueEnum<'ctx> {
+        match ty {
+            BasicTypeEnum::PointerType(ty) => ty.const_null().into(),
+            ty => ty.into_int_type().const_zero().into(),
+        }
+    }
+
+    /// Return from the current function, with zero if it returns a value.  This is done once
+    /// something has trapped, so the caller never looks at the value, and where a body ends
+    /// without a `return`.
+    fn build_unwind(&self) {
+        match self.current_fn.unwrap().get_type().get_return_type() {
+            None => self.build_return(None),
+            Some(ty) => self.build_return(Some(&self.const_zero(ty))),
+        };
+    }
+
+    /// Raise a trap with `message` and return.  Code compiled after this is unreachable, but
+    /// still goes somewhere.
+    fn build_trap(&self, message: PointerValue<'ctx>) {
+        self.builder.build_call(self.trap_function(TRAP_SYMBOL), &[message.into()], "");
+        self.build_unwind();
+        self.builder.position_at_end(self.new_block());
+    }
+
+    /// Raise a trap with `message` and return if `cond` holds.
+    fn build_trap_if(&self, cond: IntValue<'ctx>, message: &str) {
+        let trap_block = self.new_block();
+        let cont_block = self.new_block();
+        self.builder.build_conditional_branch(cond, trap_block, cont_block);
+        self.builder.position_at_end(trap_block);
+        let message = self.builder.build_global_string_ptr(message, "").as_pointer_value();
+        self.builder.build_call(self.trap_function(TRAP_SYMBOL), &[message.into()], "");
+        self.build_unwind();
+        self.builder.position_at_end(cont_block);
+    }
+
+    /// Return if the function just called trapped, passing the trap on to our caller.
+    fn build_unwind_check(&self) {
+        let trapped = self.builder.build_call(self.trap_function(TRAPPED_SYMBOL), &[], "")
+            .try_as_basic_value().left().unwrap().into_int_value();
+        let unwind_block = self.new_block();
+        let cont_block = self.new_block();
+        self.builder.build_conditional_branch(trapped, unwind_block, cont_block);
+        self.builder.position_at_end(unwind_block);
+        self.build_unwind();
+        self.builder.position_at_end(cont_block);
+    }
+
+    fn print_text(&self, text: &mut String) {
+        if !text.is_empty() {
+            let value = self.builder.build_global_string_ptr(text, "").as_pointer_value();
+            self.call_host_function("print_str", KalosType::Text, value.into());
+            text.clear();
+        }
+    }
+
+    /// Compile the `print` and `println` builtins into a call to `print_str`, `print_int` or
+    /// `print_bool` of the runtime for each piece of the format.
+    fn compile_print(&self, args: &[KalosExpr], newline: bool) -> Result<AnyValueEnum<'ctx>, KalosError> {
+        let format = match &args[0].kind {
+            KalosExprKind::StringLiteral(format) => format,
+            _ => unreachable!(),
+        };
+        let mut values = args[1..].iter();
+        let mut text = String::new();
+        for piece in parse_format(format).unwrap() {
+            match piece {
+                KalosFormatPiece::Text(s) => text.push_str(&s),
+                KalosFormatPiece::Placeholder => {
+                    self.print_text(&mut text);
+                    let value: BasicValueEnum = self.compile_expr(values.next().unwrap())?
+                        .try_into().unwrap();
+                    match value {
+                        BasicValueEnum::PointerValue(_) =>
+                            self.call_host_function("print_str", KalosType::Text, value),
+                        BasicValueEnum::IntValue(v) if v.get_type().get_bit_width() == 1 =>
+                            self.call_host_function("print_bool", KalosType::Bool, value),
+                        _ => self.call_host_function(
+                            "print_int", KalosType::Integer { signed: true, width: 64 }, value),
+                    }
+                }
+            }
+        }
+        if newline {
+            text.push('\n');
+        }
+        self.print_text(&mut text);
+        Ok(self.context.i64_type().const_zero().into())
+    }
+
+    fn compile_builtin_call(&self, name: &str, args: &[KalosExpr],
+                            span: KalosSpan) -> Result<AnyValueEnum<'ctx>, KalosError> {
+        match name {
+            "print" => return self.compile_print(args, false),
+            "println" => return self.compile_print(args, true),
+            "panic" => {
+                let message = self.compile_expr(&args[0])?.into_pointer_value();
+                self.build_trap(message);
+            }
+            "abort" => {
+                let message = self.builder.build_global_string_ptr("abort", "").as_pointer_value();
+                self.build_trap(message);
+            }
+            _ => {
+                let (mode, builtin) = explicit_arithmetic(name).unwrap();
+                let lhs = self.compile_expr(&args[0])?.into_int_value();
+                let rhs = self.compile_expr(&args[1])?.into_int_value();
+                return Ok(match mode {
+                    "wrapping" => self.build_wrapping(builtin, lhs, rhs),
+                    "checked" => self.build_checked(builtin, lhs, rhs, span),
+                    _ => self.build_saturating(builtin, lhs, rhs),
+                }.into());
+            }
+        }
+        Ok(self.context.i64_type().const_zero().into())
+    }
+
+    fn build_wrapping(&self, builtin: KalosBuiltin, lhs: IntValue<'ctx>,
+                      rhs: IntValue<'ctx>) -> IntValue<'ctx> {
+        match builtin {
+            KalosBuiltin::Add => self.builder.build_int_add(lhs, rhs, ""),
+            KalosBuiltin::Subtract => self.builder.build_int_sub(lhs, rhs, ""),
+            _ => self.builder.build_int_mul(lhs, rhs, ""),
+        }
+    }
+
+    /// Add, subtract or multiply with the `llvm.*.with.overflow` intrinsics, returning the wrapped
+    /// result and whether it overflowed.
+    fn build_with_overflow(&self, builtin: KalosBuiltin, lhs: IntValue<'ctx>,
+                           rhs: IntValue<'ctx>) -> (IntValue<'ctx>, IntValue<'ctx>) {
+        let op = match builtin {
+            KalosBuiltin::Add => "sadd",
+            KalosBuiltin::Subtract => "ssub",
+            _ => "smul",
+        };
+        let ty = lhs.get_type();
+        let name = format!("llvm.{}.with.overflow.i{}", op, ty.get_bit_width());
+        let func = self.module.get_function(&name).unwrap_or_else(|| {
+            let result_type = self.context.struct_type(&[ty.into(), self.context.bool_type().into()], false);
+            self.module.add_function(&name, result_type.fn_type(&[ty.into(), ty.into()], false), None)
+        });
+        let result = self.builder.build_call(func, &[lhs.into(), rhs.into()], "")
+            .try_as_basic_value().left().unwrap().into_struct_value();
+        let value = self.builder.build_extract_value(result, 0, "").unwrap().into_int_value();
+        let overflow = self.builder.build_extract_value(result, 1, "").unwrap().into_int_value();
+        (value, overflow)
+    }
+
+    /// Add, subtract or multiply, trapping with the location of the operation on overflow.
+    fn build_checked(&self, builtin: KalosBuiltin, lhs: IntValue<'ctx>, rhs: IntValue<'ctx>,
+                     span: KalosSpan) -> IntValue<'ctx> {
+        let (value, overflow) = self.build_with_overflow(builtin, lhs, rhs);
+        let message = match builtin {
+            KalosBuiltin::Add => "attempt to add with overflow",
+            KalosBuiltin::Subtract => "attempt to subtract with overflow",
+            _ => "attempt to multiply with overflow",
+        };
+        self.build_trap_if(overflow, &format!("{} at {}:{}", message, span.line, span.column));
+        value
+    }
+
+    /// Raise `lhs` to the power `rhs` by repeated squaring, trapping if `rhs` is negative, and on
+    /// overflow if arithmetic is checked.
+    fn build_power(&self, lhs: IntValue<'ctx>, rhs: IntValue<'ctx>,
+                   span: KalosSpan) -> IntValue<'ctx> {
+        let at = |message: &str| format!("{} at {}:{}", message, span.line, span.column);
+        let ty = lhs.get_type();
+        let zero = ty.const_zero();
+        let one = ty.const_int(1, false);
+        let negative = self.builder.build_int_compare(IntPredicate::SLT, rhs, zero, "");
+        self.build_trap_if(negative, &at("attempt to raise to a negative power"));
+        let entry_block = self.builder.get_insert_block().unwrap();
+        let cond_block = self.new_block();
+        let body_block = self.new_block();
+        let end_block = self.new_block();
+        self.builder.build_unconditional_branch(cond_block);
+
+        // while the exponent is not zero, multiply the result by the base if the exponent is odd,
+        // square the base and halve the exponent
+        self.builder.position_at_end(cond_block);
+        let result = self.builder.build_phi(ty, "");
+        let base = self.builder.build_phi(ty, "");
+        let exponent = self.builder.build_phi(ty, "");
+        let (result_value, base_value, exponent_value) = (
+            result.as_basic_value().into_int_value(),
+            base.as_basic_value().into_int_value(),
+            exponent.as_basic_value().into_int_value(),
+        );
+        let more = self.builder.build_int_compare(IntPredicate::NE, exponent_value, zero, "");
+        self.builder.build_conditional_branch(more, body_block, end_block);
+
+        self.builder.position_at_end(body_block);
+        let odd = self.builder.build_and(exponent_value, one, "");
+        let odd = self.builder.build_int_compare(IntPredicate::NE, odd, zero, "");
+        let next_exponent = self.builder.build_right_shift(exponent_value, one, false, "");
+        let (product, square) = if self.checked_arithmetic {
+            // only the products that are used may not overflow: the last square is not
+            let message = at("attempt to multiply with overflow");
+            let multiply = |x, y| self.build_with_overflow(KalosBuiltin::Multiply, x, y);
+            let (product, overflow) = multiply(result_value, base_value);
+            self.build_trap_if(self.builder.build_and(odd, overflow, ""), &message);
+            let (square, overflow) = multiply(base_value, base_value);
+            let used = self.builder.build_int_compare(IntPredicate::NE, next_exponent, zero, "");
+            self.build_trap_if(self.builder.build_and(used, overflow, ""), &message);
+            (product, square)
+        } else {
+            (self.builder.build_int_mul(result_value, base_value, ""),
+             self.builder.build_int_mul(base_value, base_value, ""))
+        };
+        let next_result = self.builder.build_select(odd, product, result_value, "")
+            .into_int_value();
+        // which `build_trap_if` may have moved on from
+        let body_block = self.builder.get_insert_block().unwrap();
+        self.builder.build_unconditional_branch(cond_block);
+
+        result.add_incoming(&[(&one, entry_block), (&next_result, body_block)]);
+        base.add_incoming(&[(&lhs, entry_block), (&square, body_block)]);
+        exponent.add_incoming(&[(&rhs, entry_block), (&next_exponent, body_block)]);
+        self.builder.position_at_end(end_block);
+        result_value
+    }
+
+    fn build_saturating(&self, builtin: KalosBuiltin, lhs: IntValue<'ctx>,
+                        rhs: IntValue<'ctx>) -> IntValue<'ctx> {
+        let (value, overflow) = self.build_with_overflow(builtin, lhs, rhs);
+        let ty = lhs.get_type();
+        let zero = ty.const_zero();
+        // which way the result overflowed: sums and differences overflow towards the sign of the
+        // left operand, products towards the sign they should have had
+        let lhs_negative = self.builder.build_int_compare(IntPredicate::SLT, lhs, zero, "");
+        let negative = if let KalosBuiltin::Multiply = builtin {
+            let rhs_negative = self.builder.build_int_compare(IntPredicate::SLT, rhs, zero, "");
+            self.builder.build_xor(lhs_negative, rhs_negative, "")
+        } else {
+            lhs_negative
+        };
+        let min = ty.const_int(1 << (ty.get_bit_width() - 1), false);
+        let max = ty.const_int((1 << (ty.get_bit_width() - 1)) - 1, false);
+        let bound = self.builder.build_select(negative, min, max, "").into_int_value();
+        self.builder.build_select(overflow, bound, value, "").into_int_value()
+    }
+
+    /// The error for using `name` at `span`, which is not in scope, suggesting names that are.
+    fn name_error(&self, name: &str, span: KalosSpan) -> KalosError {
+        KalosError::NameError { name: name.to_owned(), suggestions: similar_names(name, self.env.names()) }
+            .at(span)
+    }
+
     pub fn compile_lvalue(&self, expr: &KalosExpr) -> Result<PointerValue<'ctx>, KalosError> {
-        match expr {
-            KalosExpr::Identifier(name) =>
-                Ok(self.env.get(name).ok_or(KalosError::NameError)?.into_pointer_value()),
-            _ => Err(KalosError::LvalueError),
+        match &expr.kind {
+            KalosExprKind::Identifier(name) => Ok(self.env.get(name)
+                .ok_or_else(|| self.name_error(name, expr.span))?
+                .into_pointer_value()),
+            _ => Err(KalosError::LvalueError.at(expr.span)),
         }
     }
 
-    fn compile_builtin(&self, builtin: KalosBuiltin,
-                       args: &[KalosExpr]) -> Result<BasicValueEnum<'ctx>, KalosError> {
+    fn compile_builtin(&self, builtin: KalosBuiltin, args: &[KalosExpr],
+                       span: KalosSpan) -> Result<BasicValueEnum<'ctx>, KalosError> {
         use KalosBuiltin::*;
         let lhs = self.compile_expr(&args[0])?.into_int_value();
         let rhs = self.compile_expr(&args[1])?.into_int_value();
+        let at = |message: &str| format!("{} at {}:{}", message, span.line, span.column);
         Ok(match builtin {
-            Add => self.builder.build_int_add(lhs, rhs, ""),
-            Subtract => self.builder.build_int_sub(lhs, rhs, ""),
-            Multiply => self.builder.build_int_mul(lhs, rhs, ""),
-            Divide => self.builder.build_int_signed_div(lhs, rhs, ""),
-            Modulo => self.builder.build_int_signed_rem(lhs, rhs, ""),
-            Power => unimplemented!(),
+            Add | Subtract | Multiply if self.checked_arithmetic =>
+                self.build_checked(builtin, lhs, rhs, span),
+            Add | Subtract | Multiply => self.build_wrapping(builtin, lhs, rhs),
+            Divide | Modulo => {
+                let (by_zero, overflow) = if let Divide = builtin {
+                    ("attempt to divide by zero", "attempt to divide with overflow")
+                } else {
+                    ("attempt to calculate the remainder with a divisor of zero",
+                     "attempt to calculate the remainder with overflow")
+                };
+                let ty = lhs.get_type();
+                let is_zero = self.builder.build_int_compare(IntPredicate::EQ, rhs, ty.const_zero(), "");
+                self.build_trap_if(is_zero, &at(by_zero));
+                // the only quotient that does not fit: the smallest integer divided by -1
+                let min = ty.const_int(1 << (ty.get_bit_width() - 1), false);
+                let is_min = self.builder.build_int_compare(IntPredicate::EQ, lhs, min, "");
+                let is_minus_one = self.builder.build_int_compare(
+                    IntPredicate::EQ, rhs, ty.const_all_ones(), "");
+                self.build_trap_if(self.builder.build_and(is_min, is_minus_one, ""), &at(overflow));
+                if let Divide = builtin {
+                    self.builder.build_int_signed_div(lhs, rhs, "")
+                } else {
+                    self.builder.build_int_signed_rem(lhs, rhs, "")
+                }
+            }
+            Power => self.build_power(lhs, rhs, span),
             LessThan => self.builder.build_int_compare(IntPredicate::SLT, lhs, rhs, ""),
             LessEqual => self.builder.build_int_compare(IntPredicate::SLE, lhs, rhs, ""),
             Equal => self.builder.build_int_compare(IntPredicate::EQ, lhs, rhs, ""),
@@ -110,23 +564,40 @@ impl<'ctx> LLVMCodeGen<'ctx, '_> {
     }
 
     pub fn compile_expr(&self, expr: &KalosExpr) -> Result<AnyValueEnum<'ctx>, KalosError> {
-        use KalosExpr::*;
-        Ok(match expr {
+        use KalosExprKind::*;
+        Ok(match &expr.kind {
             UnitLiteral => unreachable!(),
             IntLiteral(x) => self.context.i64_type().const_int(*x as u64, true).into(),
             BoolLiteral(x) => self.context.bool_type().const_int(*x as u64, false).into(),
-            StringLiteral(x) => todo!(),
+            StringLiteral(x) => self.builder.build_global_string_ptr(x, "").as_pointer_value().into(),
+            Call { func, args } if builtin_function(func, &self.env).is_some() => {
+                let name = builtin_function(func, &self.env).unwrap();
+                self.compile_builtin_call(name, args, expr.span)?
+            }
             Call { func, args } => {
-                let func = self.compile_expr(func)?.into_function_value();
-                let args = args.iter().map(|e| self.compile_expr(e)
+                let func = self.compile_expr(func)?;
+                let mut args = args.iter().map(|e| self.compile_expr(e)
                     .map(|v| v.try_into().unwrap()))
                     .collect::<Result<Vec<BasicValueEnum>, KalosError>>()?;
-                self.builder.build_call(func, &args, "").try_as_basic_value()
-                    .left_or(self.context.i64_type().const_zero().into()).into()
+                let call = if let AnyValueEnum::FunctionValue(func) = func {
+                    let name = func.get_name().to_str().unwrap();
+                    if func.count_basic_blocks() == 0 && self.closures.contains(name) {
+                        args.insert(0, self.load_closure_data(name));
+                    }
+                    self.builder.build_call(func, &args, "")
+                } else {
+                    // a function of another module, loaded from its slot, see `declare_global`
+                    let func = CallableValue::try_from(func.into_pointer_value()).unwrap();
+                    self.builder.build_call(func, &args, "")
+                };
+                let value = call.try_as_basic_value()
+                    .left_or(self.context.i64_type().const_zero().into());
+                self.build_unwind_check();
+                value.into()
             }
-            Builtin { builtin, args } => self.compile_builtin(*builtin, args)?.into(),
+            Builtin { builtin, args } => self.compile_builtin(*builtin, args, expr.span)?.into(),
             Identifier(name) => {
-                let var = self.env.get(name).copied().ok_or(KalosError::NameError)?;
+                let var = self.env.get(name).copied().ok_or_else(|| self.name_error(name, expr.span))?;
                 if var.is_pointer_value() {
                     self.builder.build_load(var.into_pointer_value(), "").into()
                 } else {
@@ -137,11 +608,20 @@ impl<'ctx> LLVMCodeGen<'ctx, '_> {
     }
 
     pub fn compile_stmt(&mut self, stmt: &KalosStmt) -> Result<(), KalosError> {
-        use KalosStmt::*;
-        match stmt {
+        use KalosStmtKind::*;
+        if let Some(debug_info) = &self.debug_info {
+            debug_info.set_location(&self.builder, stmt.span);
+        }
+        match &stmt.kind {
             Compound(s) => {
                 self.env.push_empty();
+                if let Some(debug_info) = &mut self.debug_info {
+                    debug_info.enter_block(stmt.span);
+                }
                 s.iter().try_for_each(|stmt| self.compile_stmt(stmt))?;
+                if let Some(debug_info) = &mut self.debug_info {
+                    debug_info.leave_block();
+                }
                 self.env.pop();
             }
             Assignment { lhs, rhs } => {
@@ -149,21 +629,41 @@ impl<'ctx> LLVMCodeGen<'ctx, '_> {
                 let rhs: BasicValueEnum = self.compile_expr(rhs)?.try_into().unwrap();
                 self.builder.build_store(lhs, rhs);
             }
-            Var { name, ty: _ty, initializer } => {
-                let var = self.builder.build_alloca(self.context.i64_type(), name);
+            Var { name, ty, initializer } => {
+                // as in the tycker, the initializer sees an earlier variable of the same name
+                let init_val: Option<BasicValueEnum> = match initializer {
+                    Some(initializer) => Some(self.compile_expr(initializer)?.try_into().unwrap()),
+                    None => None,
+                };
+                let var_type = match init_val {
+                    Some(init_val) => init_val.get_type(),
+                    None => self.compile_type(ty).try_into().unwrap(),
+                };
+                let var = if self.env.tables.len() == 1 {
+                    // not inside any function (as in the REPL), so this is a global variable
+                    let global = self.module.add_global(var_type, None, name);
+                    global.set_initializer(&self.const_zero(var_type));
+                    global.as_pointer_value()
+                } else {
+                    self.builder.build_alloca(var_type, name)
+                };
+                if let Some(debug_info) = &self.debug_info {
+                    debug_info.declare_variable(&self.builder, var, var_type, name, None, stmt.span);
+                }
                 self.env.put(name.clone(), var.into());
-                if let Some(initializer) = initializer {
-                    let init_val: BasicValueEnum = self.compile_expr(initializer)?.try_into().unwrap();
+                if let Some(init_val) = init_val {
                     self.builder.build_store(var, init_val);
                 }
             }
             Return(expr) => {
-                if let KalosExpr::UnitLiteral = expr {
-                    self.builder.build_return(None);
+                if let KalosExprKind::UnitLiteral = expr.kind {
+                    self.build_return(None);
                 } else {
                     let expr_value: BasicValueEnum = self.compile_expr(expr)?.try_into().unwrap();
-                    self.builder.build_return(Some(&expr_value));
+                    self.build_return(Some(&expr_value));
                 }
+                // as after `build_trap`, what follows is unreachable but needs a block of its own
+                self.builder.position_at_end(self.new_block());
             }
             If { cond, then_part, else_part } => {
                 let cond_value = self.compile_expr(cond)?.into_int_value();
@@ -188,6 +688,7 @@ impl<'ctx> LLVMCodeGen<'ctx, '_> {
                 self.builder.build_conditional_branch(cond_value, loop_block, cont_block);
                 self.builder.position_at_end(loop_block);
                 self.compile_stmt(body)?;
+                self.build_safepoint();
                 let cond_value_recheck = self.compile_expr(cond)?.into_int_value();
                 self.builder.build_conditional_branch(cond_value_recheck, loop_block, cont_block);
                 self.builder.position_at_end(cont_block);
@@ -199,23 +700,106 @@ impl<'ctx> LLVMCodeGen<'ctx, '_> {
         Ok(())
     }
 
+    /// Make a global defined by another module of the `JITExecutionEngine` available to the code
+    /// compiled by `self`.  Functions are called through their slot, so that the calls reach the
+    /// latest definition even after they have been linked.
+    pub fn declare_global(&mut self, name: &str, ty: &KalosType) {
+        let value = if let KalosType::Function { signature } = ty {
+            let fn_ptr_type = self.compile_signature(signature).ptr_type(AddressSpace::Generic);
+            self.module.add_global(fn_ptr_type, None, &slot_symbol(name)).as_pointer_value()
+        } else {
+            let var_type: BasicTypeEnum = self.compile_type(ty).try_into().unwrap();
+            self.module.add_global(var_type, None, name).as_pointer_value()
+        };
+        self.env.put(name.to_owned(), value.into());
+    }
+
+    /// Declare the host functions of `runtime` that `policy` allows, see `Tycker::import_prelude`.
+    pub fn import_prelude(&mut self, runtime: &KalosRuntime, policy: &KalosPolicy) {
+        for (name, signature, _) in runtime.iter().filter(|(name, _, _)| policy.allows(name)) {
+            let func = self.declare_host_function(name, self.compile_function_type(name, signature, true));
+            self.env.put(name.to_owned(), func.into());
+        }
+    }
+
+    /// Compile a statement that is not part of any function into a new function `name`, which
+    /// takes no arguments and executes the statement.
+    pub fn compile_global_stmt(&mut self, name: &str, stmt: &KalosStmt)
+                               -> Result<FunctionValue<'ctx>, KalosError> {
+        let func = self.module.add_function(name, self.context.void_type().fn_type(&[], false), None);
+        let block = self.context.append_basic_block(func, "");
+        self.builder.position_at_end(block);
+        self.current_fn = Some(func);
+        self.build_enter_frame();
+        self.build_safepoint();
+        self.compile_stmt(stmt)?;
+        self.current_fn = None;
+        if self.builder.get_insert_block().unwrap().get_terminator().is_none() {
+            self.build_return(None);
+        }
+        assert!(func.verify(true));
+        self.fpm.run_on(&func);
+        Ok(func)
+    }
+
     pub fn compile_toplevel(&mut self, toplevel: &KalosToplevel)
                             -> Result<FunctionValue<'ctx>, KalosError> {
         match toplevel {
-            KalosToplevel::Def { name, signature, body } => {
-                let fn_type = self.compile_signature(signature);
-                let func = self.module.add_function(name, fn_type, None);
+            KalosToplevel::Def { name, signature, body, span, .. } => {
+                let fn_type = self.compile_function_type(name, signature, body.is_none());
+                let func = match self.module.get_function(name) {
+                    // an extern declaration repeating one from the prelude
+                    Some(func) if func.count_basic_blocks() == 0 && func.get_type() == fn_type => func,
+                    existing => {
+                        // a definition shadowing a prelude function replaces it, rather than getting
+                        // renamed by LLVM; the tycker makes sure that nothing has called it
+                        if let Some(func) = existing {
+                            let unused = func.as_global_value().as_pointer_value()
+                                .get_first_use().is_none();
+                            if func.count_basic_blocks() == 0 && unused {
+                                unsafe { func.delete() };
+                            }
+                        }
+                        if body.is_none() {
+                            self.declare_host_function(name, fn_type)
+                        } else {
+                            self.module.add_function(name, fn_type, None)
+                        }
+                    }
+                };
                 self.env.put(name.clone(), func.into());
                 if let Some(body) = body {
-                    self.env.push(signature.params.iter()
-                        .zip(func.get_param_iter())
-                        .map(|((name, _), param)| (name.clone(), param.into()))
-                        .collect());
                     let block = self.context.append_basic_block(func, "");
                     self.builder.position_at_end(block);
+                    if let Some(debug_info) = &mut self.debug_info {
+                        debug_info.enter_function(func, name, *span);
+                        debug_info.set_location(&self.builder, *span);
+                    }
+                    // parameters live in stack slots like any other variable, so that they can be
+                    // assigned to and described to debuggers; mem2reg cleans this up
+                    let mut params = HashMap::new();
+                    for (i, ((name, _), param)) in signature.params.iter()
+                        .zip(func.get_param_iter()).enumerate() {
+                        let var = self.builder.build_alloca(param.get_type(), name);
+                        self.builder.build_store(var, param);
+                        if let Some(debug_info) = &self.debug_info {
+                            debug_info.declare_variable(&self.builder, var, param.get_type(), name,
+                                                        Some(i as u32 + 1), *span);
+                        }
+                        params.insert(name.clone(), var.into());
+                    }
+                    self.env.push(params);
                     self.current_fn = Some(func);
+                    self.build_enter_frame();
+                    self.build_safepoint();
                     self.compile_stmt(body)?;
+                    if self.builder.get_insert_block().unwrap().get_terminator().is_none() {
+                        self.build_unwind();
+                    }
                     self.current_fn = None;
+                    if let Some(debug_info) = &mut self.debug_info {
+                        debug_info.leave_function();
+                    }
                     assert!(func.verify(true));
                     self.fpm.run_on(&func);
                     self.env.pop();
@@ -226,6 +810,10 @@ impl<'ctx> LLVMCodeGen<'ctx, '_> {
     }
 
     pub fn compile_program(&mut self, program: &KalosProgram) -> Result<(), KalosError> {
-        program.program.iter().try_for_each(|t| self.compile_toplevel(t).map(|_| ()))
+        program.program.iter().try_for_each(|t| self.compile_toplevel(t).map(|_| ()))?;
+        if let Some(debug_info) = &self.debug_info {
+            debug_info.finalize();
+        }
+        Ok(())
     }
 }
@@ -0,0 +1,145 @@
+use std::path::Path;
+
+use inkwell::attributes::AttributeLoc;
+use inkwell::builder::Builder;
+use inkwell::context::Context;
+use inkwell::debug_info::{AsDIScope, DebugInfoBuilder, DICompileUnit, DIFlags, DIFlagsConstants, DIScope, DIType, DWARFEmissionKind, DWARFSourceLanguage, debug_metadata_version};
+use inkwell::module::{FlagBehavior, Module};
+use inkwell::types::BasicTypeEnum;
+use inkwell::values::{FunctionValue, PointerValue};
+
+use crate::ast::KalosSpan;
+
+// DWARF base type encodings (DW_ATE_*), which inkwell leaves as plain integers
+const DW_ATE_BOOLEAN: u32 = 0x02;
+const DW_ATE_SIGNED: u32 = 0x05;
+
+/// DWARF metadata for a single module, with the stack of lexical scopes of the function
+/// currently being compiled.
+pub struct DebugInfo<'ctx> {
+    context: &'ctx Context,
+    dibuilder: DebugInfoBuilder<'ctx>,
+    compile_unit: DICompileUnit<'ctx>,
+    scopes: Vec<DIScope<'ctx>>,
+}
+
+impl<'ctx> DebugInfo<'ctx> {
+    pub fn new(context: &'ctx Context, module: &Module<'ctx>, path: &Path) -> Self {
+        // debuggers look source files up relative to the compilation directory, so make it absolute
+        let path = path.canonicalize().unwrap_or_else(|_| path.to_owned());
+        let filename = path.file_name().map(|s| s.to_string_lossy()).unwrap_or_default();
+        let directory = path.parent().map(|s| s.to_string_lossy()).unwrap_or_default();
+
+        module.add_basic_value_flag("Debug Info Version", FlagBehavior::Warning,
+                                    context.i32_type().const_int(debug_metadata_version() as u64, false));
+        module.add_basic_value_flag("Dwarf Version", FlagBehavior::Warning,
+                                    context.i32_type().const_int(4, false));
+
+        let (dibuilder, compile_unit) = module.create_debug_info_builder(
+            true,
+            // there is no DWARF language code for us, and C is what debuggers understand best
+            DWARFSourceLanguage::C,
+            &filename,
+            &directory,
+            "kalos",
+            false,
+            "",
+            0,
+            "",
+            DWARFEmissionKind::Full,
+            0,
+            false,
+            false,
+            "",
+            "",
+        );
+
+        Self {
+            context,
+            dibuilder,
+            compile_unit,
+            scopes: Vec::new(),
+        }
+    }
+
+    fn debug_type(&self, ty: BasicTypeEnum<'ctx>) -> Option<DIType<'ctx>> {
+        match ty {
+            BasicTypeEnum::IntType(ty) if ty.get_bit_width() == 1 =>
+                self.dibuilder.create_basic_type("bool", 8, DW_ATE_BOOLEAN, DIFlags::ZERO).ok(),
+            BasicTypeEnum::IntType(ty) =>
+                self.dibuilder.create_basic_type("int", ty.get_bit_width() as u64, DW_ATE_SIGNED,
+                                                 DIFlags::ZERO).ok(),
+            _ => None,
+        }.map(|ty| ty.as_type())
+    }
+
+    fn current_scope(&self) -> DIScope<'ctx> {
+        *self.scopes.last().unwrap()
+    }
+
+    pub fn enter_function(&mut self, func: FunctionValue<'ctx>, name: &str, span: KalosSpan) {
+        let file = self.compile_unit.get_file();
+        let fn_type = func.get_type();
+        let return_type = fn_type.get_return_type().and_then(|ty| self.debug_type(ty));
+        let param_types: Vec<DIType> = fn_type.get_param_types().into_iter()
+            .filter_map(|ty| self.debug_type(ty)).collect();
+        let subroutine_type = self.dibuilder.create_subroutine_type(
+            file, return_type, &param_types, DIFlags::PUBLIC);
+        let subprogram = self.dibuilder.create_function(
+            self.compile_unit.as_debug_info_scope(), name, None, file, span.line, subroutine_type,
+            false, true, span.line, DIFlags::PUBLIC, false);
+        func.set_subprogram(subprogram);
+        // keep frame pointers so that profilers can walk the stack through JIT-compiled code
+        func.add_attribute(AttributeLoc::Function,
+                           self.context.create_string_attribute("frame-pointer", "all"));
+        self.scopes.push(subprogram.as_debug_info_scope());
+    }
+
+    pub fn leave_function(&mut self) {
+        self.scopes.clear();
+    }
+
+    pub fn enter_block(&mut self, span: KalosSpan) {
+        let block = self.dibuilder.create_lexical_block(
+            self.current_scope(), self.compile_unit.get_file(), span.line, span.column);
+        self.scopes.push(block.as_debug_info_scope());
+    }
+
+    pub fn leave_block(&mut self) {
+        self.scopes.pop();
+    }
+
+    /// Attribute the instructions built from now on to `span`.
+    pub fn set_location(&self, builder: &Builder<'ctx>, span: KalosSpan) {
+        let location = self.dibuilder.create_debug_location(
+            self.context, span.line, span.column, self.current_scope(), None);
+        builder.set_current_debug_location(self.context, location);
+    }
+
+    /// Describe the variable stored at `storage`.  `arg_no` is the 1-based position of function
+    /// parameters and `None` for local variables.
+    pub fn declare_variable(&self, builder: &Builder<'ctx>, storage: PointerValue<'ctx>,
+                            ty: BasicTypeEnum<'ctx>, name: &str, arg_no: Option<u32>,
+                            span: KalosSpan) {
+        let ty = match self.debug_type(ty) {
+            Some(ty) => ty,
+            None => return,
+        };
+        let scope = self.current_scope();
+        let file = self.compile_unit.get_file();
+        let var = match arg_no {
+            Some(arg_no) => self.dibuilder.create_parameter_variable(
+                scope, name, arg_no, file, span.line, ty, true, DIFlags::ZERO),
+            None => self.dibuilder.create_auto_variable(
+                scope, name, file, span.line, ty, true, DIFlags::ZERO, 0),
+        };
+        let location = self.dibuilder.create_debug_location(
+            self.context, span.line, span.column, scope, None);
+        self.dibuilder.insert_declare_at_end(storage, Some(var), None, location,
+                                             builder.get_insert_block().unwrap());
+    }
+
+    pub fn finalize(&self) {
+        self.dibuilder.finalize();
+    }
+}
@@ -0,0 +1,72 @@
+use crate::ast::{KalosDiagnostic, KalosError, KalosSeverity, KalosSpan};
+use crate::json::Json;
+
+/// The line and column of `offset` in `source`, both counting from 1 as in `KalosSpan`.
+fn line_col(source: &str, offset: usize) -> (usize, usize) {
+    let before = source.get(..offset).unwrap_or(source);
+    let start = before.rfind('\n').map_or(0, |i| i + 1);
+    (before.matches('\n').count() + 1, before[start..].chars().count() + 1)
+}
+
+fn span_json(source: &str, span: KalosSpan) -> Vec<(&'static str, Json)> {
+    let (end_line, end_column) = line_col(source, span.end);
+    vec![
+        ("start", Json::from(span.start)),
+        ("end", Json::from(span.end)),
+        ("line", Json::from(span.line as usize)),
+        ("column", Json::from(span.column as usize)),
+        ("end_line", Json::from(end_line)),
+        ("end_column", Json::from(end_column)),
+    ]
+}
+
+/// What could be done about `diagnostic`, as replacements of parts of the source.
+fn fixes(source: &str, diagnostic: &KalosDiagnostic) -> Vec<Json> {
+    match (&diagnostic.error, diagnostic.span) {
+        (KalosError::NameError { suggestions, .. }, Some(span)) => suggestions.iter().map(|name| {
+            Json::object(vec![
+                ("message", Json::from(format!("use {}", name))),
+                ("span", Json::object(span_json(source, span))),
+                ("replacement", Json::from(name.as_str())),
+            ])
+        }).collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn diagnostic_json(path: &str, source: &str, diagnostic: &KalosDiagnostic) -> Json {
+    let secondary = diagnostic.notes.iter().map(|(span, label)| {
+        let mut fields = span_json(source, *span);
+        fields.push(("label", Json::from(label.as_str())));
+        Json::object(fields)
+    }).collect();
+    Json::object(vec![
+        ("severity", Json::from(match diagnostic.severity {
+            KalosSeverity::Error => "error",
+            KalosSeverity::Warning => "warning",
+        })),
+        ("code", Json::from(diagnostic.error.code())),
+        ("message", Json::from(diagnostic.error.message())),
+        ("file", Json::from(path)),
+        ("primary", diagnostic.span.map(|span| Json::object(span_json(source, span))).into()),
+        ("secondary", Json::Array(secondary)),
+        ("fixes", Json::Array(fixes(source, diagnostic))),
+    ])
+}
+
+/// `error`, found in the script at `path` whose text is `source`, as JSON for tools: one object
+/// per line for each of its diagnostics, with its `severity`, `code`, `message`, `primary` span,
+/// `secondary` spans and the `fixes` suggested for it.  Errors found in no particular place have
+/// a `null` primary span.
+pub fn diagnostics_json(path: &str, source: &str, error: &KalosError) -> String {
+    let diagnostics = match error {
+        KalosError::Diagnostics(diagnostics) => diagnostics.to_owned(),
+        error => vec![KalosDiagnostic {
+            error: error.to_owned(),
+            span: None,
+            notes: Vec::new(),
+            severity: KalosSeverity::Error,
+        }],
+    };
+    diagnostics.iter().map(|diagnostic| format!("{}\n", diagnostic_json(path, source, diagnostic))).collect()
+}
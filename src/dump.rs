@@ -0,0 +1,204 @@
+use std::fmt::Write;
+
+use crate::ast::{KalosAttribute, KalosExpr, KalosExprKind, KalosProgram, KalosSpan, KalosStmt, KalosStmtKind, KalosToplevel, KalosType};
+use crate::json::Json;
+
+/// What a node of the dump has, besides other nodes.
+enum Field {
+    Text(String),
+    Number(i64),
+    Flag(bool),
+    Node(Box<Node>),
+    List(Vec<Field>),
+    Nothing,
+}
+
+/// A node of the AST as it is dumped, which is the same for every kind of node.
+struct Node {
+    kind: &'static str,
+    span: Option<KalosSpan>,
+    fields: Vec<(&'static str, Field)>,
+}
+
+fn node(kind: &'static str, span: KalosSpan, fields: Vec<(&'static str, Field)>) -> Field {
+    Field::Node(box Node { kind, span: Some(span), fields })
+}
+
+fn text(s: &str) -> Field {
+    Field::Text(s.to_owned())
+}
+
+fn expr_node(expr: &KalosExpr) -> Field {
+    use KalosExprKind::*;
+    let span = expr.span;
+    match &expr.kind {
+        UnitLiteral => node("UnitLiteral", span, vec![]),
+        BoolLiteral(b) => node("BoolLiteral", span, vec![("value", Field::Flag(*b))]),
+        IntLiteral(n) => node("IntLiteral", span, vec![("value", Field::Number(*n))]),
+        StringLiteral(s) => node("StringLiteral", span, vec![("value", text(s))]),
+        Identifier(name) => node("Identifier", span, vec![("name", text(name))]),
+        Call { func, args } => node("Call", span, vec![
+            ("func", expr_node(func)),
+            ("args", Field::List(args.iter().map(expr_node).collect())),
+        ]),
+        Builtin { builtin, args } => node("Builtin", span, vec![
+            ("op", text(builtin.operator())),
+            ("args", Field::List(args.iter().map(expr_node).collect())),
+        ]),
+    }
+}
+
+fn stmt_node(stmt: &KalosStmt) -> Field {
+    use KalosStmtKind::*;
+    let span = stmt.span;
+    match &stmt.kind {
+        Compound(stmts) => node("Compound", span, vec![
+            ("stmts", Field::List(stmts.iter().map(stmt_node).collect())),
+        ]),
+        Assignment { lhs, rhs } => node("Assignment", span, vec![
+            ("lhs", expr_node(lhs)),
+            ("rhs", expr_node(rhs)),
+        ]),
+        Var { name, ty, initializer } => node("Var", span, vec![
+            ("name", text(name)),
+            ("ty", Field::Text(ty.to_string())),
+            ("initializer", initializer.as_ref().map_or(Field::Nothing, expr_node)),
+        ]),
+        Return(expr) => node("Return", span, vec![("value", expr_node(expr))]),
+        If { cond, then_part, else_part } => node("If", span, vec![
+            ("cond", expr_node(cond)),
+            ("then_part", stmt_node(then_part)),
+            ("else_part", else_part.as_ref().map_or(Field::Nothing, |s| stmt_node(s))),
+        ]),
+        While { cond, body } => node("While", span, vec![
+            ("cond", expr_node(cond)),
+            ("body", stmt_node(body)),
+        ]),
+        Expression(expr) => node("Expression", span, vec![("expr", expr_node(expr))]),
+    }
+}
+
+fn toplevel_node(toplevel: &KalosToplevel) -> Field {
+    match toplevel {
+        KalosToplevel::Def { name, signature, body, span, .. } => node("Def", *span, vec![
+            ("name", text(name)),
+            ("signature", Field::Text(KalosType::Function { signature: signature.clone() }.to_string())),
+            ("body", body.as_ref().map_or(Field::Nothing, stmt_node)),
+        ]),
+    }
+}
+
+fn attribute_node(attribute: &KalosAttribute) -> Field {
+    node("Attribute", attribute.span, vec![
+        ("name", text(&attribute.name)),
+        ("args", Field::List(attribute.args.iter().map(|arg| text(arg)).collect())),
+    ])
+}
+
+fn program_node(program: &KalosProgram) -> Node {
+    Node {
+        kind: "Program",
+        span: None,
+        fields: vec![
+            ("attributes", Field::List(program.attributes.iter().map(attribute_node).collect())),
+            ("program", Field::List(program.program.iter().map(toplevel_node).collect())),
+        ],
+    }
+}
+
+impl Field {
+    fn is_node(&self) -> bool {
+        matches!(self, Field::Node(_))
+    }
+
+    /// Write the field on the line of its node, unless it is a node or a list of nodes.
+    fn write_inline(&self, output: &mut String) {
+        match self {
+            Field::Text(s) => write!(output, "{:?}", s).unwrap(),
+            Field::Number(n) => write!(output, "{}", n).unwrap(),
+            Field::Flag(b) => write!(output, "{}", b).unwrap(),
+            Field::List(items) => {
+                output.push('[');
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        output.push_str(", ");
+                    }
+                    item.write_inline(output);
+                }
+                output.push(']');
+            }
+            Field::Node(_) | Field::Nothing => unreachable!(),
+        }
+    }
+
+    fn to_json(&self) -> Json {
+        match self {
+            Field::Text(s) => Json::from(s.as_str()),
+            Field::Number(n) => Json::Int(*n),
+            Field::Flag(b) => Json::Bool(*b),
+            Field::Node(node) => node.to_json(),
+            Field::List(items) => Json::Array(items.iter().map(Field::to_json).collect()),
+            Field::Nothing => Json::Null,
+        }
+    }
+}
+
+impl Node {
+    /// Write the node on a line of its own, with the scalar fields, and the nodes it has on the
+    /// lines after it, each indented and labelled with its field.
+    fn write_tree(&self, indent: usize, output: &mut String) {
+        output.push_str(self.kind);
+        if let Some(span) = self.span {
+            write!(output, " @{}:{}", span.line, span.column).unwrap();
+        }
+        let mut children = Vec::new();
+        for (name, field) in &self.fields {
+            match field {
+                Field::Nothing => (),
+                Field::Node(node) => children.push((name, node)),
+                Field::List(items) if !items.is_empty() && items.iter().all(Field::is_node) => {
+                    children.extend(items.iter().map(|item| match item {
+                        Field::Node(node) => (name, node),
+                        _ => unreachable!(),
+                    }));
+                }
+                _ => {
+                    write!(output, " {}=", name).unwrap();
+                    field.write_inline(output);
+                }
+            }
+        }
+        output.push('\n');
+        for (name, child) in children {
+            output.push_str(&"  ".repeat(indent + 1));
+            write!(output, "{}: ", name).unwrap();
+            child.write_tree(indent + 1, output);
+        }
+    }
+
+    fn to_json(&self) -> Json {
+        let mut fields = vec![("kind", Json::from(self.kind))];
+        if let Some(span) = self.span {
+            fields.push(("span", Json::object(vec![
+                ("start", Json::from(span.start)),
+                ("end", Json::from(span.end)),
+                ("line", Json::from(span.line as usize)),
+                ("column", Json::from(span.column as usize)),
+            ])));
+        }
+        fields.extend(self.fields.iter().map(|(name, field)| (*name, field.to_json())));
+        Json::object(fields)
+    }
+}
+
+/// `program` as a tree, one node per line, with where each node starts.
+pub fn dump_tree(program: &KalosProgram) -> String {
+    let mut output = String::new();
+    program_node(program).write_tree(0, &mut output);
+    output
+}
+
+/// `program` as JSON: each node is an object with its `kind`, its `span` and its fields.
+pub fn dump_json(program: &KalosProgram) -> String {
+    format!("{}\n", program_node(program).to_json())
+}
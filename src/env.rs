@@ -2,6 +2,7 @@ use std::borrow::Borrow;
 use std::collections::HashMap;
 use std::hash::Hash;
 
+#[derive(Clone)]
 pub struct Env<K, V> {
     pub tables: Vec<HashMap<K, V>>
 }
@@ -37,3 +38,48 @@ impl<K, V> Env<K, V> where K: Eq + Hash {
         self.tables.iter().rev().find_map(|t| t.get(k))
     }
 }
+
+impl<V> Env<String, V> {
+    /// The names in all the tables, outermost first.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.tables.iter().flat_map(|t| t.keys().map(String::as_str))
+    }
+}
+
+/// How many characters have to be inserted, deleted, replaced or swapped with the next one to turn
+/// `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    // distances[i][j] is that between the first i characters of `a` and the first j of `b`
+    let mut distances = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for i in 0..=a.len() {
+        for j in 0..=b.len() {
+            distances[i][j] = if i == 0 || j == 0 {
+                i + j
+            } else {
+                let replaced = distances[i - 1][j - 1] + if a[i - 1] == b[j - 1] { 0 } else { 1 };
+                let mut distance = replaced.min(distances[i - 1][j] + 1).min(distances[i][j - 1] + 1);
+                if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                    distance = distance.min(distances[i - 2][j - 2] + 1);
+                }
+                distance
+            };
+        }
+    }
+    distances[a.len()][b.len()]
+}
+
+/// The few `candidates` closest to `name`, which is misspelt, the closest first.
+pub fn similar_names<'a>(name: &str, candidates: impl Iterator<Item = &'a str>) -> Vec<String> {
+    // about one typo in three characters
+    let max_distance = (name.chars().count() / 3).max(1);
+    let mut similar: Vec<(usize, &str)> = candidates
+        .filter(|candidate| *candidate != name)
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, candidate)| *distance <= max_distance && *distance < candidate.len())
+        .collect();
+    similar.sort_unstable();
+    similar.dedup();
+    similar.into_iter().take(3).map(|(_, candidate)| candidate.to_owned()).collect()
+}
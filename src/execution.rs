@@ -1,33 +1,275 @@
-use inkwell::execution_engine::{ExecutionEngine, JitFunction};
+use std::any::Any;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+use inkwell::execution_engine::{ExecutionEngine, JitFunction, UnsafeFunctionPointer};
 use inkwell::module::Module;
 use inkwell::OptimizationLevel;
+use inkwell::values::{BasicValue, FunctionValue, GlobalValue};
+
+use crate::ast::{KalosError, KalosType};
+use crate::runtime::{catch_trap, data_symbol, DEPTH_SYMBOL, FUEL_SYMBOL, INTERRUPT_SYMBOL, KalosClosure, KalosPolicy, KalosRuntime, state_address, trap_symbols};
 
-pub struct JITExecutionEngine<'a, 'ctx> {
-    module: &'a Module<'ctx>,
+const SLOT_SUFFIX: &str = ".slot";
+
+/// The global holding the address of the current definition of the function `name`.
+pub fn slot_symbol(name: &str) -> String {
+    format!("{}{}", name, SLOT_SUFFIX)
+}
+
+/// How many frames scripts may push unless told otherwise, enough to fill a 2 MiB stack.
+pub const DEFAULT_MAX_DEPTH: u64 = 10_000;
+
+/// Interrupts the scripts run by a `JITExecutionEngine`, from any thread.
+#[derive(Clone)]
+pub struct InterruptHandle {
+    flag: Arc<AtomicBool>,
+}
+
+impl InterruptHandle {
+    /// Make the running script, or else the next one, trap with `KalosError::Interrupted`.
+    pub fn interrupt(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+    }
+}
+
+/// A JIT that modules can be added to, using and redefining the globals of earlier ones.
+pub struct JITExecutionEngine<'ctx> {
+    modules: Vec<Module<'ctx>>,
     engine: ExecutionEngine<'ctx>,
+    // the symbol of the current definition of each global
+    symbols: HashMap<String, String>,
+    slots: HashMap<String, Box<Cell<usize>>>,
+    host: &'ctx KalosRuntime,
+    runtime: HashMap<String, usize>,
+    closures: HashMap<String, Box<Cell<usize>>>,
+    states: Vec<Box<dyn Any>>,
+    fuel: Box<Cell<i64>>,
+    depth: Box<Cell<i64>>,
+    interrupt: InterruptHandle,
 }
 
-impl<'a, 'ctx> JITExecutionEngine<'a, 'ctx> {
-    pub fn new(module: &'a Module<'ctx>) -> Self {
-        Self {
-            module,
-            engine: module.create_jit_execution_engine(OptimizationLevel::Default).unwrap(),
+impl<'ctx> JITExecutionEngine<'ctx> {
+    pub fn new(module: Module<'ctx>, runtime: &'ctx KalosRuntime) -> Result<Self, KalosError> {
+        Self::with_policy(module, runtime, &KalosPolicy::allow_all())
+    }
+
+    /// An engine whose modules may only link against the host functions `policy` allows.
+    pub fn with_policy(module: Module<'ctx>, runtime: &'ctx KalosRuntime,
+                       policy: &KalosPolicy) -> Result<Self, KalosError> {
+        let engine = module.create_jit_execution_engine(OptimizationLevel::Default).unwrap();
+        let fuel = Box::new(Cell::new(i64::MAX));
+        let depth = Box::new(Cell::new(DEFAULT_MAX_DEPTH as i64));
+        let interrupt = InterruptHandle { flag: Arc::new(AtomicBool::new(false)) };
+        let host = runtime;
+        let mut runtime: HashMap<String, usize> = host.symbols()
+            .filter(|(name, _)| policy.allows(name))
+            .chain(trap_symbols())
+            .collect();
+        let closures: HashMap<String, Box<Cell<usize>>> = host.closures()
+            .filter(|(name, _)| policy.allows(name))
+            .map(|(name, state)| (name.to_owned(), Box::new(Cell::new(state))))
+            .collect();
+        for (name, state) in &closures {
+            runtime.insert(data_symbol(name), &**state as *const Cell<usize> as usize);
         }
+        runtime.insert(FUEL_SYMBOL.to_owned(), &*fuel as *const Cell<i64> as usize);
+        runtime.insert(DEPTH_SYMBOL.to_owned(), &*depth as *const Cell<i64> as usize);
+        runtime.insert(INTERRUPT_SYMBOL.to_owned(), &*interrupt.flag as *const AtomicBool as usize);
+        let mut engine = Self {
+            modules: Vec::new(),
+            engine,
+            symbols: HashMap::new(),
+            slots: HashMap::new(),
+            host,
+            runtime,
+            closures,
+            states: Vec::new(),
+            fuel,
+            depth,
+            interrupt,
+        };
+        engine.link(&module)?;
+        engine.modules.push(module);
+        Ok(engine)
     }
 
-    pub fn get_main(&self) -> JitFunction<'ctx, unsafe extern "C" fn()> {
-        unsafe { self.engine.get_function("main") }.unwrap()
+    pub fn add_module(&mut self, module: Module<'ctx>) -> Result<(), KalosError> {
+        let defined = self.link(&module)?;
+        self.engine.add_module(&module).unwrap();
+        for name in defined {
+            if let Some(slot) = self.slots.get(&name) {
+                slot.set(self.engine.get_function_address(&self.symbols[&name]).unwrap());
+            }
+        }
+        self.modules.push(module);
+        Ok(())
     }
-}
 
-impl JITExecutionEngine<'_, '_> {
-    pub fn attach_runtime<'a, T>(&self, runtime: impl IntoIterator<Item=&'a (&'a T, usize)>)
-        where T: 'a + ?Sized + AsRef<str>   // can't pretend I understand what I wrote
+    pub unsafe fn get_function<F>(&self, name: &str) -> Option<JitFunction<'ctx, F>>
+        where F: UnsafeFunctionPointer
     {
-        for (name, addr) in runtime {
-            if let Some(func) = self.module.get_function(name.as_ref()) {
-                self.engine.add_global_mapping(&func, *addr);
+        let symbol = self.symbols.get(name).map_or(name, |s| s.as_str());
+        self.engine.get_function(symbol).ok()
+    }
+
+    /// Call `main`, returning what it returns if that is an integer, and 0 otherwise.
+    pub unsafe fn call_main(&self) -> i64 {
+        let returns_status = self.function("main").expect("some main thing failed")
+            .get_type().get_return_type().is_some();
+        if returns_status {
+            self.get_function::<unsafe extern "C" fn() -> i64>("main").unwrap().call()
+        } else {
+            self.get_function::<unsafe extern "C" fn()>("main").unwrap().call();
+            0
+        }
+    }
+
+    /// Limit the scripts to `fuel` more function calls and loop iterations, or lift the limit.
+    pub fn set_fuel(&self, fuel: Option<u64>) {
+        self.fuel.set(fuel.map_or(i64::MAX, |fuel| fuel.min(i64::MAX as u64) as i64));
+    }
+
+    /// What is left of the budget set by `set_fuel`.
+    pub fn remaining_fuel(&self) -> u64 {
+        self.fuel.get().max(0) as u64
+    }
+
+    /// Limit the scripts to `depth` nested calls.  Not to be called while a script is running.
+    pub fn set_max_depth(&self, depth: u64) {
+        self.depth.set(depth.min(i64::MAX as u64) as i64);
+    }
+
+    /// Call `func` for the closure `name` of the runtime in the scripts of this engine only.
+    pub fn set_closure<Args, F: KalosClosure<Args>>(&mut self, name: &str,
+                                                    func: F) -> Result<(), KalosError> {
+        let cell = self.closures.get(name).ok_or_else(|| KalosError::LinkError(name.to_owned()))?;
+        let expect = self.host.get(name).unwrap();
+        if !expect.is_compatible(&F::signature()) {
+            return Err(KalosError::ExternError {
+                name: name.to_owned(),
+                expect: KalosType::Function { signature: expect.to_owned() },
+                found: KalosType::Function { signature: F::signature() },
+            });
+        }
+        let state = func.into_state();
+        cell.set(state_address(&*state));
+        self.states.push(state);
+        Ok(())
+    }
+
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.interrupt.clone()
+    }
+
+    /// Forget an interrupt made while no script was running.
+    pub fn clear_interrupt(&self) {
+        self.interrupt.flag.store(false, Ordering::SeqCst);
+    }
+
+    /// Run `f` as `catch_trap` does, interrupting it once `timeout` has passed.
+    pub fn call_with_timeout<R>(&self, timeout: Duration,
+                                f: impl FnOnce() -> R) -> Result<R, KalosError> {
+        self.clear_interrupt();
+        let interrupt = self.interrupt_handle();
+        let (done, watchdog) = channel::<()>();
+        let watchdog = thread::spawn(move || {
+            if let Err(RecvTimeoutError::Timeout) = watchdog.recv_timeout(timeout) {
+                interrupt.interrupt();
+            }
+        });
+        let result = catch_trap(f);
+        drop(done);
+        watchdog.join().unwrap();
+        result
+    }
+
+    fn function(&self, name: &str) -> Option<FunctionValue<'ctx>> {
+        let symbol = self.symbols.get(name)?;
+        self.modules.iter()
+            .find_map(|module| module.get_function(symbol).filter(|f| f.count_basic_blocks() > 0))
+    }
+
+    fn define(&mut self, name: String, value: GlobalValue<'ctx>) {
+        let symbol = if self.symbols.contains_key(&name) {
+            // no identifier contains a dot, so this cannot clash with anything in the scripts
+            let symbol = format!("{}.{}", name, self.modules.len());
+            value.as_pointer_value().set_name(&symbol);
+            symbol
+        } else {
+            name.clone()
+        };
+        self.symbols.insert(name, symbol);
+    }
+
+    fn slot(&mut self, name: &str) -> &Cell<usize> {
+        if !self.slots.contains_key(name) {
+            let addr = self.engine.get_function_address(&self.symbols[name]).unwrap();
+            self.slots.insert(name.to_owned(), Box::new(Cell::new(addr)));
+        }
+        &self.slots[name]
+    }
+
+    fn resolve(&mut self, name: &str, value: GlobalValue<'ctx>) {
+        let addr = if let Some(function) = name.strip_suffix(SLOT_SUFFIX) {
+            Some(self.slot(function) as *const Cell<usize> as usize)
+        } else if let Some(symbol) = self.symbols.get(name) {
+            self.engine.get_function_address(symbol).ok()
+        } else {
+            self.runtime.get(name).copied()
+        };
+        if let Some(addr) = addr {
+            self.engine.add_global_mapping(&value, addr);
+        }
+    }
+
+    fn link(&mut self, module: &Module<'ctx>) -> Result<Vec<String>, KalosError> {
+        let mut definitions = Vec::new();
+        let mut declarations = Vec::new();
+        let mut func = module.get_first_function();
+        while let Some(f) = func {
+            let name = f.get_name().to_str().unwrap().to_owned();
+            if f.count_basic_blocks() > 0 {
+                // linked code calls it through its slot with the old type
+                if self.function(&name).map_or(false, |old| old.get_type() != f.get_type()) {
+                    return Err(KalosError::RedefinitionError(name));
+                }
+                definitions.push((name, f.as_global_value()));
+            } else if !name.starts_with("llvm.") {
+                // intrinsics are not linked against anything
+                declarations.push((name, f.as_global_value()));
+            }
+            func = f.get_next_function();
+        }
+        let mut global = module.get_first_global();
+        while let Some(g) = global {
+            let name = g.get_name().to_str().unwrap().to_owned();
+            if g.get_initializer().is_some() {
+                definitions.push((name, g));
+            } else {
+                declarations.push((name, g));
             }
+            global = g.get_next_global();
+        }
+        let unresolved = declarations.iter().find(|(name, _)| match name.strip_suffix(SLOT_SUFFIX) {
+            Some(function) => !self.symbols.contains_key(function),
+            None => !self.symbols.contains_key(name) && !self.runtime.contains_key(name),
+        });
+        if let Some((name, _)) = unresolved {
+            return Err(KalosError::LinkError(name.to_owned()));
+        }
+        let defined = definitions.iter().map(|(name, _)| name.to_owned()).collect();
+        for (name, value) in definitions {
+            self.define(name, value);
+        }
+        for (name, value) in declarations {
+            self.resolve(&name, value);
         }
+        Ok(defined)
     }
 }
@@ -0,0 +1,347 @@
+use std::fmt::Display;
+
+use crate::ast::{KalosAttribute, KalosExpr, KalosExprKind, KalosProgram, KalosSignature, KalosStmt, KalosStmtKind, KalosToplevel, KalosType};
+use crate::parser::precedence;
+
+/// The byte ranges of the comments in `source`, which the parser skips.
+fn find_comments(source: &str) -> Vec<(usize, usize)> {
+    let bytes = source.as_bytes();
+    let mut comments = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'"' => {
+                i += 1;
+                while i < bytes.len() && bytes[i] != b'"' {
+                    i += if bytes[i] == b'\\' { 2 } else { 1 };
+                }
+                i += 1;
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                let end = source[i + 2..].find("*/").map_or(bytes.len(), |j| i + 2 + j + 2);
+                comments.push((i, end));
+                i = end;
+            }
+            _ => i += 1,
+        }
+    }
+    comments
+}
+
+fn format_string(string: &str) -> String {
+    let mut result = String::from("\"");
+    for c in string.chars() {
+        match c {
+            '\n' => result.push_str("\\n"),
+            '\t' => result.push_str("\\t"),
+            '"' | '\\' => {
+                result.push('\\');
+                result.push(c);
+            }
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
+
+fn format_type(ty: &KalosType) -> String {
+    match ty {
+        KalosType::Auto => "auto".to_owned(),
+        KalosType::Bool => "bool".to_owned(),
+        KalosType::Integer { signed: true, width: 64 } => "int".to_owned(),
+        ty => ty.to_string(),
+    }
+}
+
+fn format_signature(signature: &KalosSignature) -> String {
+    let mut params: Vec<String> = signature.params.iter()
+        .map(|(name, ty)| format!("{}: {}", name, format_type(ty)))
+        .collect();
+    if signature.variadic {
+        params.push("...".to_owned());
+    }
+    match &*signature.return_type {
+        KalosType::Unit => format!("({})", params.join(", ")),
+        ty => format!("({}) -> {}", params.join(", "), format_type(ty)),
+    }
+}
+
+/// `expr` with as few parentheses as it takes to parse back the same way.
+pub fn format_expr(expr: &KalosExpr) -> String {
+    use KalosExprKind::*;
+    match &expr.kind {
+        UnitLiteral => "()".to_owned(),
+        BoolLiteral(b) => b.to_string(),
+        IntLiteral(n) => n.to_string(),
+        StringLiteral(s) => format_string(s),
+        Identifier(name) => name.to_owned(),
+        Call { func, args } => {
+            let args: Vec<String> = args.iter().map(format_expr).collect();
+            let func = match func.kind {
+                Builtin { .. } | Call { .. } => format!("({})", format_expr(func)),
+                _ => format_expr(func),
+            };
+            format!("{}({})", func, args.join(", "))
+        }
+        Builtin { builtin, args } => {
+            let (prec, right) = precedence(*builtin);
+            // an operand needs parentheses if it binds more loosely than the operator, or just as
+            // tightly on the side the operator does not associate to
+            let operand = |operand: &KalosExpr, left: bool| match &operand.kind {
+                Builtin { builtin, .. } => {
+                    let inner = precedence(*builtin).0;
+                    if inner < prec || inner == prec && left == right {
+                        format!("({})", format_expr(operand))
+                    } else {
+                        format_expr(operand)
+                    }
+                }
+                _ => format_expr(operand),
+            };
+            format!("{} {} {}", operand(&args[0], true), builtin.operator(), operand(&args[1], false))
+        }
+    }
+}
+
+/// Lays out a program in the canonical style, putting back the comments of its source.
+struct Formatter<'a> {
+    source: &'a str,
+    comments: Vec<(usize, usize)>,
+    // the comments that have been written so far
+    next_comment: usize,
+    output: String,
+    indent: usize,
+}
+
+impl<'a> Formatter<'a> {
+    fn new(source: &'a str) -> Self {
+        Self {
+            source,
+            comments: find_comments(source),
+            next_comment: 0,
+            output: String::new(),
+            indent: 0,
+        }
+    }
+
+    fn write_indent(&mut self) {
+        for _ in 0..self.indent {
+            self.output.push('\t');
+        }
+    }
+
+    /// Whether there is a blank line in the source right before `start`.
+    fn blank_line_before(&self, start: usize) -> bool {
+        // nodes built without a source have no layout to keep
+        match self.source.get(..start) {
+            Some(before) => before[before.trim_end().len()..].matches('\n').count() > 1,
+            None => false,
+        }
+    }
+
+    /// Separate what comes at `start` from what has been written, by a blank line if the source
+    /// has one there or if `force`.
+    fn separate(&mut self, start: usize, force: bool) {
+        if !self.output.is_empty() && !self.output.ends_with("{\n") &&
+            (force || self.blank_line_before(start)) {
+            self.output.push('\n');
+        }
+    }
+
+    /// Write the comments before `start` on lines of their own, except for those on the line
+    /// where what has just been written, which ends at `end`, ends.  The first comment on a line of
+    /// its own is separated from what comes before as by `separate`.  Returns whether there was
+    /// any.
+    fn write_comments(&mut self, start: usize, end: Option<usize>, force: bool) -> bool {
+        let mut own_line = false;
+        while let Some(&(comment_start, comment_end)) = self.comments.get(self.next_comment) {
+            if comment_start >= start {
+                break;
+            }
+            let trailing = !own_line && matches!(end, Some(end) if end > comment_start ||
+                !self.source[end..comment_start].contains('\n'));
+            if trailing && self.output.ends_with('\n') {
+                self.output.pop();
+                self.output.push(' ');
+            } else {
+                self.separate(comment_start, force && !own_line);
+                self.write_indent();
+                own_line = true;
+            }
+            self.output.push_str(&self.source[comment_start..comment_end]);
+            self.output.push('\n');
+            self.next_comment += 1;
+        }
+        own_line
+    }
+
+    /// Write the comments before `start` on the current line.
+    fn write_inline_comments(&mut self, start: usize) {
+        while let Some(&(comment_start, comment_end)) = self.comments.get(self.next_comment) {
+            if comment_start >= start {
+                break;
+            }
+            self.output.push_str(&self.source[comment_start..comment_end]);
+            self.output.push(' ');
+            self.next_comment += 1;
+        }
+    }
+
+    fn write_attribute(&mut self, attribute: &KalosAttribute) {
+        self.output.push_str("#![");
+        self.output.push_str(&attribute.name);
+        if !attribute.args.is_empty() {
+            self.output.push_str(&format!("({})", attribute.args.join(", ")));
+        }
+        self.output.push_str("]\n");
+    }
+
+    /// Write `stmt`, which starts where the current line is, leaving the line open.
+    fn write_stmt(&mut self, stmt: &KalosStmt) {
+        use KalosStmtKind::*;
+        match &stmt.kind {
+            Compound(stmts) => {
+                // such as those between the condition of an `if` and its block
+                self.write_inline_comments(stmt.span.start);
+                self.output.push_str("{\n");
+                self.indent += 1;
+                let mut end = None;
+                for stmt in stmts {
+                    self.write_comments(stmt.span.start, end, false);
+                    self.separate(stmt.span.start, false);
+                    self.write_indent();
+                    self.write_stmt(stmt);
+                    self.output.push('\n');
+                    end = Some(stmt.span.end);
+                }
+                // the comments before the closing brace
+                self.write_comments(stmt.span.end.saturating_sub(1), end, false);
+                self.indent -= 1;
+                if stmts.is_empty() && self.output.ends_with("{\n") {
+                    self.output.pop();
+                } else {
+                    self.write_indent();
+                }
+                self.output.push('}');
+            }
+            Assignment { lhs, rhs } => {
+                self.output.push_str(&format!("{} = {};", format_expr(lhs), format_expr(rhs)));
+            }
+            Var { name, ty, initializer } => {
+                self.output.push_str("var ");
+                self.output.push_str(name);
+                if *ty != KalosType::Auto {
+                    self.output.push_str(&format!(": {}", format_type(ty)));
+                }
+                if let Some(initializer) = initializer {
+                    self.output.push_str(&format!(" = {}", format_expr(initializer)));
+                }
+                self.output.push(';');
+            }
+            Return(expr) => match expr.kind {
+                KalosExprKind::UnitLiteral => self.output.push_str("return;"),
+                _ => self.output.push_str(&format!("return {};", format_expr(expr))),
+            },
+            If { cond, then_part, else_part } => {
+                self.output.push_str(&format!("if ({}) ", format_expr(cond)));
+                self.write_stmt(then_part);
+                if let Some(else_part) = else_part {
+                    self.output.push_str(" else ");
+                    self.write_stmt(else_part);
+                }
+            }
+            While { cond, body } => {
+                self.output.push_str(&format!("while ({}) ", format_expr(cond)));
+                self.write_stmt(body);
+            }
+            Expression(expr) => {
+                self.output.push_str(&format_expr(expr));
+                self.output.push(';');
+            }
+        }
+    }
+
+    fn write_toplevel(&mut self, toplevel: &KalosToplevel) {
+        match toplevel {
+            KalosToplevel::Def { name, signature, body, .. } => {
+                self.output.push_str(&format!("def {}{}", name, format_signature(signature)));
+                match body {
+                    Some(body) => {
+                        self.output.push(' ');
+                        self.write_stmt(body);
+                    }
+                    None => self.output.push_str(" extern;"),
+                }
+                self.output.push('\n');
+            }
+        }
+    }
+
+    fn write_program(&mut self, program: &KalosProgram) {
+        let mut end = None;
+        for attribute in &program.attributes {
+            self.write_comments(attribute.span.start, end, false);
+            self.write_attribute(attribute);
+            end = Some(attribute.span.end);
+        }
+        for toplevel in &program.program {
+            let span = toplevel.span();
+            // definitions are set apart along with the comments right before them
+            let commented = self.write_comments(span.start, end, end.is_some());
+            self.separate(span.start, end.is_some() && !commented);
+            self.write_toplevel(toplevel);
+            end = Some(span.end);
+        }
+        self.write_comments(self.source.len(), end, false);
+    }
+}
+
+/// `stmt` laid out as by `format_program`, without comments.
+pub fn format_stmt(stmt: &KalosStmt) -> String {
+    let mut formatter = Formatter::new("");
+    formatter.write_stmt(stmt);
+    formatter.output
+}
+
+/// `toplevel` laid out as by `format_program`, without comments.
+pub fn format_toplevel(toplevel: &KalosToplevel) -> String {
+    let mut formatter = Formatter::new("");
+    formatter.write_toplevel(toplevel);
+    formatter.output.pop();
+    formatter.output
+}
+
+/// `program`, parsed from `source`, laid out in the canonical style: one statement per line,
+/// indented by tabs, with a blank line between definitions and as few parentheses as possible.
+/// Comments are kept, although those within a statement move to its end.
+pub fn format_program(source: &str, program: &KalosProgram) -> String {
+    let mut formatter = Formatter::new(source);
+    formatter.write_program(program);
+    formatter.output
+}
+
+/// As it would be written in the source, see `format_program`.
+impl Display for KalosExpr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", format_expr(self))
+    }
+}
+
+impl Display for KalosStmt {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", format_stmt(self))
+    }
+}
+
+impl Display for KalosToplevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", format_toplevel(self))
+    }
+}
+
+impl Display for KalosProgram {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", format_program("", self))
+    }
+}
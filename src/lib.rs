@@ -1,52 +1,332 @@
 #![feature(box_syntax)]
 #![feature(c_variadic)]
 
+use std::collections::HashMap;
 use std::fs::read_to_string;
-use std::io::Write;
+use std::io::{Read, stdin};
+use std::path::Path;
+use std::time::Duration;
 
 use inkwell::context::Context;
-use pest::Parser;
+use inkwell::module::Module;
+use inkwell::{IntPredicate, OptimizationLevel};
+use inkwell::targets::{CodeModel, FileType, InitializationConfig, RelocMode, Target, TargetMachine};
 
+use crate::ast::KalosToplevel;
 use crate::codegen::LLVMCodeGen;
-use crate::execution::JITExecutionEngine;
-use crate::parser::{KalosParser, parse_program, Rule};
-pub use crate::runtime::DEFAULT_RUNTIME;
-use crate::tyck::Tycker;
+pub use crate::diagnostics::diagnostics_json;
+use crate::dump::{dump_json, dump_tree};
+use crate::formatter::format_program;
+pub use crate::execution::{DEFAULT_MAX_DEPTH, InterruptHandle, JITExecutionEngine};
+use crate::parser::parse_source;
+use crate::runtime::scoped_lines;
+pub use crate::repl::repl;
+pub use crate::ast::{KalosDiagnostic, KalosError, KalosLint, KalosProgram, KalosSeverity, KalosSignature, KalosSpan, KalosType};
+pub use crate::runtime::{catch_trap, default_runtime, KalosClosure, KalosHostFunction, KalosPolicy, KalosRuntime, KalosValue};
+pub use crate::json::Json;
+pub use crate::lint::KalosLevel;
+use crate::lint::lint_program;
+pub use crate::tyck::{KalosBinding, KalosIndex, KalosReference};
+use crate::tyck::{tyck_main, Tycker};
 
 mod ast;
 mod parser;
 mod env;
 mod tyck;
 mod codegen;
+mod debuginfo;
+mod diagnostics;
+mod dump;
 mod execution;
+mod formatter;
+mod json;
+mod lint;
 mod runtime;
+mod repl;
 
-pub fn run<'a, T>(filename: &str, runtime: impl IntoIterator<Item=&'a (&'a T, usize)>)
-    where T: 'a + ?Sized + AsRef<str>
-{
-    let input = read_to_string(filename).expect("some read thing failed");
-    let parse = KalosParser::parse(Rule::program, &input).expect("some parse thing failed");
-    let program = parse_program(parse);
-    let mut tycker = Tycker::new();
-    tycker.tyck_program(&program).expect("some type thing failed");
+/// How scripts are compiled.
+#[derive(Clone, Debug, Default)]
+pub struct KalosOptions {
+    /// The host functions the script may use.
+    pub policy: KalosPolicy,
+    /// See `LLVMCodeGen::check_arithmetic`.
+    pub checked_arithmetic: bool,
+    /// The levels of lints, which the attributes of the script override.  Those not given are
+    /// at `KalosLevel::Warn`.
+    pub lints: HashMap<KalosLint, KalosLevel>,
+}
 
-    let context = Context::create();
-    let module = context.create_module("");
-    let mut codegen = LLVMCodeGen::new(&context, &module);
-    codegen.compile_program(&program).expect("some compile thing failed");
+/// Limits on running a script, which keep untrusted scripts from running forever.
+#[derive(Clone, Debug, Default)]
+pub struct KalosLimits {
+    /// See `JITExecutionEngine::set_fuel`.
+    pub fuel: Option<u64>,
+    /// See `JITExecutionEngine::call_with_timeout`.
+    pub timeout: Option<Duration>,
+    /// See `JITExecutionEngine::set_max_depth`, `None` keeps the default.
+    pub max_depth: Option<u64>,
+}
+
+/// What checking a script found out about it, for tools such as the language server.
+#[derive(Debug, Default)]
+pub struct KalosAnalysis {
+    /// The errors in the script.
+    pub diagnostics: Vec<KalosDiagnostic>,
+    /// The names the script binds and uses, as far as it could be checked.
+    pub index: KalosIndex,
+}
+
+/// Read the script at `path`, or from stdin if `path` is `-`.
+pub fn read_source(path: &str) -> std::io::Result<String> {
+    if path == "-" {
+        let mut source = String::new();
+        stdin().read_to_string(&mut source)?;
+        Ok(source)
+    } else {
+        read_to_string(path)
+    }
+}
+
+/// Parse the script `source` without checking it, reporting all its syntax errors as
+/// `KalosError::Diagnostics`.
+pub fn parse(source: &str) -> Result<KalosProgram, KalosError> {
+    match parse_source(source) {
+        (Some(program), diagnostics) if diagnostics.is_empty() => Ok(program),
+        (_, diagnostics) => Err(KalosError::Diagnostics(diagnostics)),
+    }
+}
+
+/// Parse and check `source`, which may use the host functions of `runtime` allowed by `options`,
+/// and lint it.  Lints at the deny level are errors, reported along with the warnings.
+fn check_source(source: &str, runtime: &KalosRuntime,
+                options: &KalosOptions) -> Result<(KalosProgram, Vec<KalosDiagnostic>), KalosError> {
+    let program = parse(source)?;
+    let mut tycker = Tycker::with_runtime(runtime, &options.policy);
+    if program.uses_prelude() {
+        tycker.import_prelude();
+    }
+    tycker.tyck_program(&program)?;
+    let diagnostics = lint_program(&program, &options.lints);
+    if diagnostics.iter().any(|d| d.severity == KalosSeverity::Error) {
+        return Err(KalosError::Diagnostics(diagnostics));
+    }
+    Ok((program, diagnostics))
+}
 
+fn compile_program<'ctx>(context: &'ctx Context, name: &str, program: &KalosProgram,
+                         runtime: &KalosRuntime, options: &KalosOptions) -> Result<Module<'ctx>, KalosError> {
+    let module = context.create_module(name);
     {
-        let stderr = std::io::stderr();
-        let mut stderr = stderr.lock();
-        writeln!(&mut stderr, "file: {}", filename).unwrap();
-        for (name, ty) in tycker.get_globals() {
-            writeln!(&mut stderr, "{}: {}", name, ty).unwrap();
+        let mut codegen = LLVMCodeGen::with_runtime(context, &module, runtime);
+        codegen.emit_debug_info(Path::new(name));
+        if options.checked_arithmetic {
+            codegen.check_arithmetic();
+        }
+        if program.uses_prelude() {
+            codegen.import_prelude(runtime, &options.policy);
+        }
+        codegen.compile_program(program)?;
+    }
+    Ok(module)
+}
+
+/// The AST of `source` as a tree, one node per line, for debugging the parser.
+pub fn dump_ast(source: &str) -> Result<String, KalosError> {
+    Ok(dump_tree(&parse(source)?))
+}
+
+/// The AST of `source` as JSON, for tools.
+pub fn dump_ast_json(source: &str) -> Result<String, KalosError> {
+    Ok(dump_json(&parse(source)?))
+}
+
+/// The script `source` laid out in the canonical style, which parses to the same program.
+pub fn format_source(source: &str) -> Result<String, KalosError> {
+    Ok(format_program(source, &parse(source)?))
+}
+
+/// Check the script `source`, recording what its names refer to even if it has errors.  Unlike
+/// `check`, this does not require `main`.
+pub fn analyze(source: &str, runtime: &KalosRuntime, options: &KalosOptions) -> KalosAnalysis {
+    let (program, syntax_errors) = parse_source(source);
+    let program = match program {
+        Some(program) => program,
+        None => return KalosAnalysis { diagnostics: syntax_errors, index: KalosIndex::default() },
+    };
+    let mut tycker = Tycker::with_runtime(runtime, &options.policy);
+    tycker.record_index();
+    if program.uses_prelude() {
+        tycker.import_prelude();
+    }
+    let diagnostics = match tycker.tyck_program(&program) {
+        // what the parser skipped may well be the cause of any other errors
+        _ if !syntax_errors.is_empty() => syntax_errors,
+        Ok(()) => lint_program(&program, &options.lints),
+        Err(KalosError::Diagnostics(diagnostics)) => diagnostics,
+        Err(error) => vec![KalosDiagnostic {
+            error,
+            span: None,
+            notes: Vec::new(),
+            severity: KalosSeverity::Error,
+        }],
+    };
+    KalosAnalysis { diagnostics, index: tycker.take_index() }
+}
+
+/// Check the script `source` for errors without compiling it.
+pub fn check(source: &str, runtime: &KalosRuntime, options: &KalosOptions) -> Result<(), KalosError> {
+    tyck_main(&check_source(source, runtime, options)?.0)
+}
+
+/// Check the script `source` as `check` does, returning the warnings about it.
+pub fn lint_source(source: &str, runtime: &KalosRuntime,
+                   options: &KalosOptions) -> Result<Vec<KalosDiagnostic>, KalosError> {
+    let (program, warnings) = check_source(source, runtime, options)?;
+    tyck_main(&program)?;
+    Ok(warnings)
+}
+
+/// Compile the script `source`, read from the file `name`, into a module ready to be added to a
+/// `JITExecutionEngine` for `runtime`.  The script need not define `main`, as modules added to a
+/// running engine do not.
+pub fn compile<'ctx>(context: &'ctx Context, name: &str, source: &str, runtime: &KalosRuntime,
+                     options: &KalosOptions) -> Result<Module<'ctx>, KalosError> {
+    let (program, _) = check_source(source, runtime, options)?;
+    compile_program(context, name, &program, runtime, options)
+}
+
+fn native_target_machine(module: &Module) -> Result<TargetMachine, KalosError> {
+    Target::initialize_native(&InitializationConfig::default()).map_err(KalosError::TargetError)?;
+    let triple = TargetMachine::get_default_triple();
+    let target = Target::from_triple(&triple).map_err(|e| KalosError::TargetError(e.to_string()))?;
+    let machine = target.create_target_machine(
+        &triple,
+        &TargetMachine::get_host_cpu_name().to_string(),
+        &TargetMachine::get_host_cpu_features().to_string(),
+        OptimizationLevel::Default,
+        RelocMode::PIC,
+        CodeModel::Default,
+    ).ok_or_else(|| KalosError::TargetError(format!("no target machine for {:?}", triple)))?;
+    module.set_triple(&triple);
+    module.set_data_layout(&machine.get_target_data().get_data_layout());
+    Ok(machine)
+}
+
+/// The assembly of `module` for the host machine.
+pub fn emit_assembly(module: &Module) -> Result<String, KalosError> {
+    let buffer = native_target_machine(module)?.write_to_memory_buffer(module, FileType::Assembly)
+        .map_err(|e| KalosError::TargetError(e.to_string()))?;
+    Ok(String::from_utf8_lossy(buffer.as_slice()).into_owned())
+}
+
+/// Write `module` to `path` as an object file for the host machine, to be linked against a
+/// runtime providing the host functions it uses.
+pub fn emit_object(module: &Module, path: &Path) -> Result<(), KalosError> {
+    native_target_machine(module)?.write_to_file(module, FileType::Object, path)
+        .map_err(|e| KalosError::IoError(format!("cannot write {}: {}", path.display(), e)))
+}
+
+/// The symbol that the `main` of a script is renamed to by `build_object`.
+const ENTRY_SYMBOL: &str = "kalos.main";
+
+/// Compile the script `source`, read from the file `name`, into an object file at `path` that
+/// links into an executable with `runtime/kalos_runtime.c`, which provides the default runtime
+/// and the traps, see `default_runtime` and `catch_trap`.  The object defines a C `main`, which
+/// calls that of the script and exits with its status, see `exit_status`.  `runtime` must not
+/// have closures: the state they are passed is only provided by a `JITExecutionEngine`.
+pub fn build_object(name: &str, source: &str, runtime: &KalosRuntime, options: &KalosOptions,
+                    path: &Path) -> Result<(), KalosError> {
+    let (program, _) = check_source(source, runtime, options)?;
+    tyck_main(&program)?;
+    let context = Context::create();
+    let module = compile_program(&context, name, &program, runtime, options)?;
+    let script_main = module.get_function("main").unwrap();
+    script_main.as_global_value().as_pointer_value().set_name(ENTRY_SYMBOL);
+    let i32_type = context.i32_type();
+    let main = module.add_function("main", i32_type.fn_type(&[], false), None);
+    let builder = context.create_builder();
+    builder.position_at_end(context.append_basic_block(main, ""));
+    let status = match builder.build_call(script_main, &[], "").try_as_basic_value().left() {
+        Some(status) => {
+            // as `exit_status` does
+            let status = status.into_int_value();
+            let i64_type = context.i64_type();
+            let in_range = builder.build_int_compare(
+                IntPredicate::ULT, status, i64_type.const_int(256, false), "");
+            let status = builder.build_select(in_range, status, i64_type.const_int(1, false), "");
+            builder.build_int_truncate(status.into_int_value(), i32_type, "")
+        }
+        None => i32_type.const_zero(),
+    };
+    builder.build_return(Some(&status));
+    emit_object(&module, path)
+}
+
+/// Call `f`, which calls into the scripts run by `engine`, within `limits`.
+fn call_with_limits<R>(engine: &JITExecutionEngine, limits: &KalosLimits,
+                       f: impl FnOnce() -> R) -> Result<R, KalosError> {
+    engine.set_fuel(limits.fuel);
+    if let Some(max_depth) = limits.max_depth {
+        engine.set_max_depth(max_depth);
+    }
+    match limits.timeout {
+        Some(timeout) => engine.call_with_timeout(timeout, f),
+        None => {
+            // an interrupt left over from an earlier run is not meant for this one
+            engine.clear_interrupt();
+            catch_trap(f)
         }
-        module.print_to_stderr();
     }
+}
+
+/// The status a process exits with when its script returns `status`, which is 1 if `status` is
+/// outside of 0 to 255 rather than truncated to a success.
+pub fn exit_status(status: i64) -> i32 {
+    if (0..256).contains(&status) { status as i32 } else { 1 }
+}
+
+/// Run the script in `filename`, returning its exit status, or the error it trapped with.
+pub fn run(filename: &str, runtime: &KalosRuntime) -> Result<i64, KalosError> {
+    run_with(filename, runtime, &KalosOptions::default(), &KalosLimits::default())
+}
+
+/// Run the script in `filename`, compiled with `options`, within `limits`, returning its exit
+/// status, or the error it trapped with.
+pub fn run_with(filename: &str, runtime: &KalosRuntime, options: &KalosOptions,
+                limits: &KalosLimits) -> Result<i64, KalosError> {
+    let source = read_source(filename)
+        .map_err(|e| KalosError::IoError(format!("cannot read {}: {}", filename, e)))?;
+    run_source(filename, &source, runtime, options, limits)
+}
 
-    let engine = JITExecutionEngine::new(&module);
-    engine.attach_runtime(runtime);
-    let fn_main = engine.get_main();
-    unsafe { fn_main.call() }
+/// Run the script `source`, read from the file `name`.
+pub fn run_source(name: &str, source: &str, runtime: &KalosRuntime, options: &KalosOptions,
+                  limits: &KalosLimits) -> Result<i64, KalosError> {
+    let (program, _) = check_source(source, runtime, options)?;
+    tyck_main(&program)?;
+    let context = Context::create();
+    let module = compile_program(&context, name, &program, runtime, options)?;
+    let engine = JITExecutionEngine::with_policy(module, runtime, &options.policy)?;
+    scoped_lines(|| call_with_limits(&engine, limits, || unsafe { engine.call_main() }))
+}
+
+/// Run the tests in the script `source`, which are the functions named `test_*` that take and
+/// return nothing.  A test fails if it traps.  The script need not define `main`.
+pub fn run_tests(name: &str, source: &str, runtime: &KalosRuntime, options: &KalosOptions,
+                 limits: &KalosLimits) -> Result<Vec<(String, Result<(), KalosError>)>, KalosError> {
+    let (program, _) = check_source(source, runtime, options)?;
+    let tests: Vec<String> = program.program.iter().filter_map(|t| match t {
+        KalosToplevel::Def { name, signature, body: Some(_), .. }
+        if name.starts_with("test_") && signature.params.is_empty() &&
+            *signature.return_type == KalosType::Unit => Some(name.to_owned()),
+        _ => None,
+    }).collect();
+    let context = Context::create();
+    let module = compile_program(&context, name, &program, runtime, options)?;
+    let engine = JITExecutionEngine::with_policy(module, runtime, &options.policy)?;
+    Ok(scoped_lines(|| tests.into_iter().map(|test| {
+        let func = unsafe { engine.get_function::<unsafe extern "C" fn()>(&test) }.unwrap();
+        let result = call_with_limits(&engine, limits, || unsafe { func.call() });
+        (test, result)
+    }).collect()))
 }
@@ -0,0 +1,270 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+use crate::ast::{KalosBuiltin, KalosDiagnostic, KalosError, KalosExpr, KalosExprKind, KalosLint, KalosProgram, KalosSeverity, KalosSpan, KalosStmt, KalosStmtKind, KalosToplevel};
+
+/// What becomes of what a lint finds: nothing, a warning or an error.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum KalosLevel {
+    Allow,
+    Warn,
+    Deny,
+}
+
+impl KalosLevel {
+    /// The level set by an attribute or a command line option of this name.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "allow" => Some(KalosLevel::Allow),
+            "warn" => Some(KalosLevel::Warn),
+            "deny" => Some(KalosLevel::Deny),
+            _ => None,
+        }
+    }
+}
+
+/// A variable or parameter in scope, and whether it has been read.
+struct Local {
+    name: String,
+    definition: KalosSpan,
+    parameter: bool,
+    used: bool,
+}
+
+struct Linter {
+    levels: HashMap<KalosLint, KalosLevel>,
+    diagnostics: Vec<KalosDiagnostic>,
+    scopes: Vec<Vec<Local>>,
+    // the functions defined in the script, and whether they are used other than by themselves
+    functions: HashMap<String, (KalosSpan, bool)>,
+    current_fn: Option<String>,
+}
+
+/// The value of `expr` if it is made of literals only, with booleans as 0 and 1.
+fn evaluate(expr: &KalosExpr) -> Option<i64> {
+    use KalosBuiltin::*;
+    match &expr.kind {
+        KalosExprKind::IntLiteral(n) => Some(*n),
+        KalosExprKind::BoolLiteral(b) => Some(*b as i64),
+        KalosExprKind::Builtin { builtin, args } => {
+            let (x, y) = (evaluate(&args[0])?, evaluate(&args[1])?);
+            match builtin {
+                Add => x.checked_add(y),
+                Subtract => x.checked_sub(y),
+                Multiply => x.checked_mul(y),
+                Divide => x.checked_div(y),
+                Modulo => x.checked_rem(y),
+                Power => x.checked_pow(y.try_into().ok()?),
+                LessThan => Some((x < y) as i64),
+                LessEqual => Some((x <= y) as i64),
+                Equal => Some((x == y) as i64),
+                GreaterEqual => Some((x >= y) as i64),
+                GreaterThan => Some((x > y) as i64),
+                NotEqual => Some((x != y) as i64),
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Whether running `stmt` always ends in a `return`.
+fn always_returns(stmt: &KalosStmt) -> bool {
+    match &stmt.kind {
+        KalosStmtKind::Return(_) => true,
+        KalosStmtKind::Compound(stmts) => stmts.iter().any(always_returns),
+        KalosStmtKind::If { then_part, else_part: Some(else_part), .. } =>
+            always_returns(then_part) && always_returns(else_part),
+        _ => false,
+    }
+}
+
+impl Linter {
+    fn emit(&mut self, lint: KalosLint, span: KalosSpan, message: String, notes: Vec<(KalosSpan, String)>) {
+        let severity = match self.levels.get(&lint).copied().unwrap_or(KalosLevel::Warn) {
+            KalosLevel::Allow => return,
+            KalosLevel::Warn => KalosSeverity::Warning,
+            KalosLevel::Deny => KalosSeverity::Error,
+        };
+        self.diagnostics.push(KalosDiagnostic {
+            error: KalosError::Lint { lint, message },
+            span: Some(span),
+            notes,
+            severity,
+        });
+    }
+
+    /// Set the levels of the lints named by the `allow`, `warn` and `deny` attributes of `program`.
+    fn read_attributes(&mut self, program: &KalosProgram) {
+        for attribute in &program.attributes {
+            let level = match KalosLevel::from_name(&attribute.name) {
+                Some(level) => level,
+                None => continue,
+            };
+            for name in &attribute.args {
+                match KalosLint::from_name(name) {
+                    Some(lint) => { self.levels.insert(lint, level); }
+                    None => self.diagnostics.push(KalosDiagnostic {
+                        error: KalosError::AttributeError(format!("unknown lint {}", name)),
+                        span: Some(attribute.span),
+                        notes: Vec::new(),
+                        severity: KalosSeverity::Error,
+                    }),
+                }
+            }
+        }
+    }
+
+    fn lookup(&mut self, name: &str) -> Option<&mut Local> {
+        self.scopes.iter_mut().rev().flat_map(|scope| scope.iter_mut().rev()).find(|local| local.name == name)
+    }
+
+    fn declare(&mut self, name: &str, definition: KalosSpan, parameter: bool) {
+        if let Some(earlier) = self.lookup(name).map(|local| local.definition) {
+            self.emit(KalosLint::Shadowing, definition, format!("{} shadows an earlier declaration", name),
+                      vec![(earlier, format!("{} is declared here", name))]);
+        }
+        self.scopes.last_mut().unwrap().push(Local { name: name.to_owned(), definition, parameter, used: false });
+    }
+
+    fn pop_scope(&mut self) {
+        for local in self.scopes.pop().unwrap() {
+            if !local.used {
+                let (lint, what) = if local.parameter {
+                    (KalosLint::UnusedParameters, "parameter")
+                } else {
+                    (KalosLint::UnusedVariables, "variable")
+                };
+                self.emit(lint, local.definition, format!("{} {} is never used", what, local.name), vec![]);
+            }
+        }
+    }
+
+    fn lint_expr(&mut self, expr: &KalosExpr) {
+        match &expr.kind {
+            KalosExprKind::Identifier(name) => {
+                if let Some(local) = self.lookup(name) {
+                    local.used = true;
+                } else if self.current_fn.as_ref() != Some(name) {
+                    if let Some((_, used)) = self.functions.get_mut(name) {
+                        *used = true;
+                    }
+                }
+            }
+            KalosExprKind::Call { func, args } => {
+                self.lint_expr(func);
+                args.iter().for_each(|arg| self.lint_expr(arg));
+            }
+            KalosExprKind::Builtin { args, .. } => args.iter().for_each(|arg| self.lint_expr(arg)),
+            _ => (),
+        }
+    }
+
+    fn lint_condition(&mut self, cond: &KalosExpr) {
+        self.lint_expr(cond);
+        if let Some(value) = evaluate(cond) {
+            let message = format!("the condition is always {}", value != 0);
+            self.emit(KalosLint::ConstantConditions, cond.span, message, vec![]);
+        }
+    }
+
+    fn lint_stmt(&mut self, stmt: &KalosStmt) {
+        match &stmt.kind {
+            KalosStmtKind::Compound(stmts) => {
+                self.scopes.push(Vec::new());
+                // where the block returns, until the statement after it is reported
+                let (mut returned, mut reported) = (None, false);
+                for stmt in stmts {
+                    if let Some(span) = returned.take() {
+                        self.emit(KalosLint::UnreachableCode, stmt.span, "unreachable statement".to_owned(),
+                                  vec![(span, "any code after this is unreachable".to_owned())]);
+                        reported = true;
+                    } else if !reported && always_returns(stmt) {
+                        returned = Some(stmt.span);
+                    }
+                    self.lint_stmt(stmt);
+                }
+                self.pop_scope();
+            }
+            KalosStmtKind::Assignment { lhs, rhs } => {
+                // assigning to a variable is not using it
+                if !matches!(lhs.kind, KalosExprKind::Identifier(_)) {
+                    self.lint_expr(lhs);
+                }
+                self.lint_expr(rhs);
+            }
+            KalosStmtKind::Var { name, initializer, .. } => {
+                if let Some(initializer) = initializer {
+                    self.lint_expr(initializer);
+                }
+                self.declare(name, stmt.span, false);
+            }
+            KalosStmtKind::Return(expr) | KalosStmtKind::Expression(expr) => self.lint_expr(expr),
+            KalosStmtKind::If { cond, then_part, else_part } => {
+                self.lint_condition(cond);
+                self.lint_stmt(then_part);
+                if let Some(else_part) = else_part {
+                    self.lint_stmt(else_part);
+                }
+            }
+            // the way to write a loop that only ends by returning
+            KalosStmtKind::While { cond, body } if cond.kind == KalosExprKind::BoolLiteral(true) =>
+                self.lint_stmt(body),
+            KalosStmtKind::While { cond, body } => {
+                self.lint_condition(cond);
+                self.lint_stmt(body);
+            }
+        }
+    }
+
+    fn lint_program(&mut self, program: &KalosProgram) {
+        for toplevel in &program.program {
+            match toplevel {
+                KalosToplevel::Def { name, body: Some(_), span, .. } => {
+                    self.functions.insert(name.to_owned(), (*span, false));
+                }
+                KalosToplevel::Def { .. } => (),
+            }
+        }
+        for toplevel in &program.program {
+            match toplevel {
+                KalosToplevel::Def { name, signature, body: Some(body), param_spans, .. } => {
+                    self.current_fn = Some(name.to_owned());
+                    self.scopes.push(Vec::new());
+                    for ((param, _), span) in signature.params.iter().zip(param_spans) {
+                        self.declare(param, *span, true);
+                    }
+                    self.lint_stmt(body);
+                    self.pop_scope();
+                }
+                KalosToplevel::Def { .. } => (),
+            }
+        }
+        self.current_fn = None;
+        let mut unused: Vec<(String, KalosSpan)> = self.functions.iter()
+            // `main` and the tests are called by the host
+            .filter(|(name, (_, used))| !used && *name != "main" && !name.starts_with("test_"))
+            .map(|(name, (span, _))| (name.to_owned(), *span))
+            .collect();
+        unused.sort_by_key(|(_, span)| span.start);
+        for (name, span) in unused {
+            self.emit(KalosLint::UnusedFunctions, span, format!("function {} is never used", name), vec![]);
+        }
+    }
+}
+
+/// Lint `program`, which must have been checked, at the levels of `levels` unless its attributes
+/// set them otherwise.  Lints at the `Deny` level are reported as errors, the others as warnings,
+/// in the order they appear in the source.
+pub fn lint_program(program: &KalosProgram, levels: &HashMap<KalosLint, KalosLevel>) -> Vec<KalosDiagnostic> {
+    let mut linter = Linter {
+        levels: levels.to_owned(),
+        diagnostics: Vec::new(),
+        scopes: Vec::new(),
+        functions: HashMap::new(),
+        current_fn: None,
+    };
+    linter.read_attributes(program);
+    linter.lint_program(program);
+    linter.diagnostics.sort_by_key(|d| d.span.map(|span| span.start));
+    linter.diagnostics
+}
@@ -1,6 +1,274 @@
-use kalos::{run, DEFAULT_RUNTIME};
+use std::fs::write;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+
+use inkwell::context::Context;
+use kalos::{analyze, build_object, compile, default_runtime, diagnostics_json, dump_ast, dump_ast_json, emit_assembly, exit_status, format_source, KalosDiagnostic, KalosError, KalosLevel, KalosLimits, KalosLint, KalosOptions, KalosRuntime, KalosSeverity, lint_source, read_source, repl, run_source, run_tests};
+
+const USAGE: &str = "\
+usage: kalos [<options>] <command> [<args>]
+
+commands:
+    run <file> [-- <args>...]     compile and run a script, passing <args> to it
+    check <file>                  check a script for errors and warnings without running it
+    build <file> [-o <output>]    compile a script into an object file, which links into an
+                                  executable with runtime/kalos_runtime.c of the Kalos sources
+    emit <what> <file>            print the AST (ast, or ast-json for JSON), LLVM IR (ir) or
+                                  assembly (asm) of a script
+    test <file>...                run the test_* functions of scripts
+    fmt [--check] <file>...       format scripts in place, or with --check list those that are not
+                                  formatted
+    repl                          evaluate definitions, statements and expressions interactively
+
+options:
+    --error-format=<format>       report errors in scripts as text (human, the default) or as JSON,
+                                  one object per line (json)
+    --allow=<lint>, --warn=<lint>, --deny=<lint>
+                                  set the level of a lint, unless the script sets it with an
+                                  attribute such as #![allow(<lint>)]
+
+lints: unused_variables, unused_functions, unused_parameters, shadowing, unreachable_code and
+constant_conditions, all of them warned about by default.
+
+<file> may be - to read the script from stdin.
+
+exit status: that of the script for `run`, 1 if the script has errors, a test fails or a script
+is not formatted, 2 for usage errors and 101 if the script traps.";
+
+fn usage_error(message: &str) -> ! {
+    eprintln!("error: {}\n\n{}", message, USAGE);
+    exit(2)
+}
+
+fn read_or_exit(path: &str) -> String {
+    read_source(path).unwrap_or_else(|e| {
+        eprintln!("error: cannot read {}: {}", path, e);
+        exit(1)
+    })
+}
+
+/// How errors in scripts are reported, see `--error-format`.
+#[derive(Clone, Copy)]
+enum ErrorFormat {
+    Human,
+    Json,
+}
+
+/// Take the options out of `args`, where they may come anywhere before `--`, setting the levels
+/// of lints in `options` and returning the error format.
+fn take_options(args: &mut Vec<String>, options: &mut KalosOptions) -> ErrorFormat {
+    let mut error_format = ErrorFormat::Human;
+    let mut i = 0;
+    while i < args.len() && args[i] != "--" {
+        let (name, value) = match args[i].strip_prefix("--").and_then(|arg| Some((arg, arg.find('=')?))) {
+            Some((arg, j)) => (arg[..j].to_owned(), arg[j + 1..].to_owned()),
+            None => {
+                i += 1;
+                continue;
+            }
+        };
+        if name == "error-format" {
+            error_format = match value.as_str() {
+                "human" => ErrorFormat::Human,
+                "json" => ErrorFormat::Json,
+                _ => usage_error(&format!("unknown error format {}", value)),
+            };
+        } else if let Some(level) = KalosLevel::from_name(&name) {
+            let lint = KalosLint::from_name(&value)
+                .unwrap_or_else(|| usage_error(&format!("unknown lint {}", value)));
+            options.lints.insert(lint, level);
+        } else {
+            i += 1;
+            continue;
+        }
+        args.remove(i);
+    }
+    error_format
+}
+
+/// Print `diagnostics`, found in the script at `path` whose text is `source`.
+fn print_diagnostics(path: &str, source: &str, diagnostics: Vec<KalosDiagnostic>, format: ErrorFormat) {
+    match format {
+        ErrorFormat::Json => eprint!("{}", diagnostics_json(path, source, &KalosError::Diagnostics(diagnostics))),
+        ErrorFormat::Human => {
+            for diagnostic in diagnostics {
+                eprintln!("{}:{}", path, diagnostic);
+                for (span, note) in diagnostic.notes {
+                    eprintln!("{}:{}:{}: note: {}", path, span.line, span.column, note);
+                }
+            }
+        }
+    }
+}
+
+/// Print the warnings about the script at `path` whose text is `source`, before running or
+/// compiling it.  Its errors are left to be reported by what it is run or compiled with.
+fn print_warnings(path: &str, source: &str, runtime: &KalosRuntime, options: &KalosOptions,
+                  format: ErrorFormat) {
+    let warnings: Vec<KalosDiagnostic> = analyze(source, runtime, options).diagnostics.into_iter()
+        .filter(|diagnostic| diagnostic.severity == KalosSeverity::Warning)
+        .collect();
+    if !warnings.is_empty() {
+        print_diagnostics(path, source, warnings, format);
+    }
+}
+
+/// Report `error`, found in the script at `path` whose text is `source`, and exit.
+fn report(path: &str, source: &str, error: KalosError, format: ErrorFormat) -> ! {
+    match (format, error) {
+        (_, KalosError::Diagnostics(diagnostics)) => print_diagnostics(path, source, diagnostics, format),
+        (ErrorFormat::Json, error) => eprint!("{}", diagnostics_json(path, source, &error)),
+        (ErrorFormat::Human, error) => eprintln!("{}: {}", path, error),
+    }
+    exit(1)
+}
+
+/// The single file argument of a command.
+fn file_arg(args: &[String]) -> &str {
+    match args {
+        [path] => path,
+        [] => usage_error("missing file"),
+        _ => usage_error("too many arguments"),
+    }
+}
 
 fn main() {
-    let filename = std::env::args().nth(1).expect("some arg thing failed");
-    run(&filename, &*DEFAULT_RUNTIME);
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let mut options = KalosOptions::default();
+    let error_format = take_options(&mut args, &mut options);
+    let (command, args) = match args.split_first() {
+        Some((command, args)) => (command.as_str(), args),
+        None => usage_error("missing command"),
+    };
+    let mut runtime = default_runtime();
+    match command {
+        "run" => {
+            let (path, script_args) = match args.iter().position(|arg| arg == "--") {
+                Some(i) => (file_arg(&args[..i]), args[i + 1..].to_vec()),
+                None => (file_arg(args), Vec::new()),
+            };
+            let source = read_or_exit(path);
+            runtime.register_args(script_args);
+            print_warnings(path, &source, &runtime, &options, error_format);
+            match run_source(path, &source, &runtime, &options, &KalosLimits::default()) {
+                Ok(status) => exit(exit_status(status)),
+                Err(e) if e.is_trap() => {
+                    eprintln!("{}: {}", path, e);
+                    exit(101)
+                }
+                Err(e) => report(path, &source, e, error_format),
+            }
+        }
+        "check" => {
+            let path = file_arg(args);
+            let source = read_or_exit(path);
+            match lint_source(&source, &runtime, &options) {
+                Ok(warnings) => print_diagnostics(path, &source, warnings, error_format),
+                Err(e) => report(path, &source, e, error_format),
+            }
+        }
+        "build" => {
+            let (path, output) = match args {
+                [path] => (path.as_str(), None),
+                [path, flag, output] if flag == "-o" => (path.as_str(), Some(PathBuf::from(output))),
+                _ => usage_error("expected <file> [-o <output>]"),
+            };
+            let output = output.unwrap_or_else(|| {
+                if path == "-" { PathBuf::from("a.o") } else { Path::new(path).with_extension("o") }
+            });
+            let source = read_or_exit(path);
+            print_warnings(path, &source, &runtime, &options, error_format);
+            build_object(path, &source, &runtime, &options, &output)
+                .unwrap_or_else(|e| report(path, &source, e, error_format));
+        }
+        "emit" => {
+            let (what, path) = match args {
+                [what, path] => (what.as_str(), path.as_str()),
+                _ => usage_error("expected <ast|ast-json|ir|asm> <file>"),
+            };
+            let source = read_or_exit(path);
+            if what == "ir" || what == "asm" {
+                print_warnings(path, &source, &runtime, &options, error_format);
+            }
+            let context = Context::create();
+            let output = match what {
+                "ast" => dump_ast(&source),
+                "ast-json" => dump_ast_json(&source),
+                "ir" => compile(&context, path, &source, &runtime, &options)
+                    .map(|module| module.print_to_string().to_string()),
+                "asm" => compile(&context, path, &source, &runtime, &options)
+                    .and_then(|module| emit_assembly(&module)),
+                _ => usage_error(&format!("cannot emit {}", what)),
+            };
+            print!("{}", output.unwrap_or_else(|e| report(path, &source, e, error_format)));
+        }
+        "test" => {
+            if args.is_empty() {
+                usage_error("missing file");
+            }
+            let (mut passed, mut failed) = (0, 0);
+            for path in args {
+                let source = read_or_exit(path);
+                print_warnings(path, &source, &runtime, &options, error_format);
+                let results = run_tests(path, &source, &runtime, &options, &KalosLimits::default())
+                    .unwrap_or_else(|e| report(path, &source, e, error_format));
+                for (test, result) in results {
+                    match result {
+                        Ok(()) => {
+                            println!("test {}::{} ... ok", path, test);
+                            passed += 1;
+                        }
+                        Err(e) => {
+                            println!("test {}::{} ... FAILED: {}", path, test, e);
+                            failed += 1;
+                        }
+                    }
+                }
+            }
+            println!("\ntest result: {}. {} passed; {} failed",
+                     if failed == 0 { "ok" } else { "FAILED" }, passed, failed);
+            if failed > 0 {
+                exit(1);
+            }
+        }
+        "fmt" => {
+            let (check_only, paths) = match args.split_first() {
+                Some((flag, paths)) if flag == "--check" => (true, paths),
+                _ => (false, args),
+            };
+            if paths.is_empty() {
+                usage_error("missing file");
+            }
+            let mut unformatted = false;
+            for path in paths {
+                let source = read_or_exit(path);
+                let formatted = format_source(&source)
+                    .unwrap_or_else(|e| report(path, &source, e, error_format));
+                if check_only {
+                    if formatted != source {
+                        println!("{}", path);
+                        unformatted = true;
+                    }
+                } else if path == "-" {
+                    print!("{}", formatted);
+                } else if formatted != source {
+                    if let Err(e) = write(path, formatted) {
+                        eprintln!("error: cannot write {}: {}", path, e);
+                        exit(1);
+                    }
+                }
+            }
+            if unformatted {
+                exit(1);
+            }
+        }
+        "repl" => {
+            if !args.is_empty() {
+                usage_error("too many arguments");
+            }
+            repl(&runtime);
+        }
+        "help" | "--help" | "-h" => println!("{}", USAGE),
+        _ => usage_error(&format!("unknown command {}", command)),
+    }
 }
@@ -1,9 +1,11 @@
+use pest::error::{Error, ErrorVariant, InputLocation};
 use pest::iterators::{Pair, Pairs};
 use pest::prec_climber;
 use pest::prec_climber::PrecClimber;
+use pest::{Parser, Position, Span};
 use pest_derive::Parser;
 
-use crate::ast::{KalosBuiltin::*, KalosExpr::{self, *}, KalosProgram, KalosSignature, KalosStmt, KalosToplevel, KalosType};
+use crate::ast::{KalosAttribute, KalosDiagnostic, KalosError, KalosBuiltin, KalosBuiltin::*, KalosExpr, KalosFormatPiece, KalosExprKind::*, KalosProgram, KalosSeverity, KalosSignature, KalosSpan, KalosStmt, KalosStmtKind, KalosToplevel, KalosType};
 
 #[derive(Parser)]
 #[grammar = "kalos.pest"]
@@ -17,18 +19,90 @@ const PREC_CLIMBER: PrecClimber<Rule> = prec_climber![
     R   power,
 ];
 
+/// How tightly a binary operator binds and whether it is right associative, which must agree with
+/// `PREC_CLIMBER`.
+pub fn precedence(op: KalosBuiltin) -> (u32, bool) {
+    match op {
+        Equal | NotEqual => (1, false),
+        LessThan | LessEqual | GreaterThan | GreaterEqual => (2, false),
+        Add | Subtract => (3, false),
+        Multiply | Divide | Modulo => (4, false),
+        Power => (5, true),
+    }
+}
+
+fn parse_span(span: Span) -> KalosSpan {
+    let (line, column) = span.start_pos().line_col();
+    KalosSpan {
+        start: span.start(),
+        end: span.end(),
+        line: line as u32,
+        column: column as u32,
+    }
+}
+
 fn parse_identifier(id: Pair<Rule>) -> String {
     assert!(id.as_rule() == Rule::identifier);
     id.as_str().to_owned()
 }
 
+fn parse_string(string: Pair<Rule>) -> String {
+    assert!(string.as_rule() == Rule::string);
+    let quoted = string.as_str();
+    let mut result = String::new();
+    let mut chars = quoted[1..quoted.len() - 1].chars();
+    while let Some(c) = chars.next() {
+        result.push(if c == '\\' {
+            match chars.next().unwrap() {
+                'n' => '\n',
+                't' => '\t',
+                c => c,
+            }
+        } else { c });
+    }
+    result
+}
+
+/// Split a format string into pieces, or `None` if it has unmatched braces.  Literal braces are
+/// written as `{{` and `}}`.
+pub fn parse_format(format: &str) -> Option<Vec<KalosFormatPiece>> {
+    let mut pieces = Vec::new();
+    let mut text = String::new();
+    let mut chars = format.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek().copied()) {
+            ('{', Some('{')) | ('}', Some('}')) => {
+                chars.next();
+                text.push(c);
+            }
+            ('{', Some('}')) => {
+                chars.next();
+                if !text.is_empty() {
+                    pieces.push(KalosFormatPiece::Text(std::mem::take(&mut text)));
+                }
+                pieces.push(KalosFormatPiece::Placeholder);
+            }
+            ('{', _) | ('}', _) => return None,
+            _ => text.push(c),
+        }
+    }
+    if !text.is_empty() {
+        pieces.push(KalosFormatPiece::Text(text));
+    }
+    Some(pieces)
+}
+
 fn parse_atom(atom: Pair<Rule>) -> KalosExpr {
-    match atom.as_rule() {
+    let span = parse_span(atom.as_span());
+    let kind = match atom.as_rule() {
         Rule::literal => IntLiteral(atom.as_str().parse::<i64>().unwrap()),
+        Rule::string => StringLiteral(parse_string(atom)),
+        Rule::boolean_literal => BoolLiteral(atom.as_str() == "true"),
         Rule::identifier => Identifier(parse_identifier(atom)),
-        Rule::expr => parse_expr(atom),
+        Rule::expr => return parse_expr(atom),
         _ => unreachable!(),
-    }
+    };
+    KalosExpr { kind, span }
 }
 
 fn parse_type(type_expr: Pair<Rule>) -> KalosType {
@@ -48,10 +122,11 @@ pub fn parse_expr(expr: Pair<Rule>) -> KalosExpr {
         expr.into_inner(),
         |pair: Pair<Rule>| match pair.as_rule() {
             Rule::call => {
+                let span = parse_span(pair.as_span());
                 let mut parts = pair.into_inner();
                 let func = box parse_atom(parts.next().unwrap());
                 let args = parts.next().unwrap().into_inner().map(parse_expr).collect();
-                Call { func, args }
+                KalosExpr { kind: Call { func, args }, span }
             }
             _ => parse_atom(pair),
         },
@@ -71,14 +146,16 @@ pub fn parse_expr(expr: Pair<Rule>) -> KalosExpr {
                 Rule::not_equal => NotEqual,
                 _ => unreachable!(),
             };
-            Builtin { builtin: op, args: vec![lhs, rhs] }
+            let span = lhs.span.to(rhs.span);
+            KalosExpr { kind: Builtin { builtin: op, args: vec![lhs, rhs] }, span }
         },
     )
 }
 
 pub fn parse_stmt(stmt: Pair<Rule>) -> KalosStmt {
-    use KalosStmt::*;
-    match stmt.as_rule() {
+    use KalosStmtKind::*;
+    let span = parse_span(stmt.as_span());
+    let kind = match stmt.as_rule() {
         Rule::assignment_stmt => {
             let mut parts = stmt.into_inner();
             let lhs = parse_expr(parts.next().unwrap());
@@ -98,8 +175,8 @@ pub fn parse_stmt(stmt: Pair<Rule>) -> KalosStmt {
             });
             Var { name, ty, initializer }
         }
-        Rule::return_stmt =>
-            Return(stmt.into_inner().next().map(parse_expr).unwrap_or(UnitLiteral)),
+        Rule::return_stmt => Return(stmt.into_inner().next().map(parse_expr)
+            .unwrap_or(KalosExpr { kind: UnitLiteral, span })),
         Rule::if_stmt => {
             let mut parts = stmt.into_inner();
             let cond = parse_expr(parts.next().unwrap());
@@ -115,22 +192,26 @@ pub fn parse_stmt(stmt: Pair<Rule>) -> KalosStmt {
         }
         Rule::expr_stmt => Expression(parse_expr(stmt.into_inner().next().unwrap())),
         _ => unreachable!(),
-    }
+    };
+    KalosStmt { kind, span }
 }
 
-fn parse_signature(signature: Pair<Rule>) -> KalosSignature {
+/// The signature, and the span of each of its parameters.
+fn parse_signature(signature: Pair<Rule>) -> (KalosSignature, Vec<KalosSpan>) {
     assert!(signature.as_rule() == Rule::signature);
     let mut parts = signature.into_inner();
 
     // FIXME: is there a more idiomatic way for this?  (fold works but makes little difference)
     let mut variadic = false;
     let mut params = Vec::new();
+    let mut param_spans = Vec::new();
     for p in parts.next().unwrap().into_inner() {
         match p.as_rule() {
             Rule::param => {
+                param_spans.push(parse_span(p.as_span()));
                 let mut parts = p.into_inner();
                 let name = parse_identifier(parts.next().unwrap());
-                let ty = parse_type(parts.next().unwrap());
+                let ty = parse_type(parts.nth(1).unwrap());
                 params.push((name, ty));
             }
             Rule::ellipsis => { variadic = true; }
@@ -138,29 +219,232 @@ fn parse_signature(signature: Pair<Rule>) -> KalosSignature {
         }
     }
     let return_type = box parts.next().map(parse_type).unwrap_or(KalosType::Unit);
-    KalosSignature {
+    let signature = KalosSignature {
         params,
         return_type,
         variadic,
-    }
+    };
+    (signature, param_spans)
 }
 
 pub fn parse_toplevel(t: Pair<Rule>) -> KalosToplevel {
     match t.as_rule() {
         Rule::def => {
+            let span = parse_span(t.as_span());
             let mut parts = t.into_inner();
-            let name = parts.next().unwrap().as_str().to_owned();
-            let signature = parse_signature(parts.next().unwrap());
+            let name = parts.next().unwrap();
+            let name_span = parse_span(name.as_span());
+            let name = name.as_str().to_owned();
+            let (signature, param_spans) = parse_signature(parts.next().unwrap());
             let body = parts.next().map(parse_stmt);
-            KalosToplevel::Def { name, signature, body }
+            KalosToplevel::Def { name, signature, body, span, name_span, param_spans }
         }
         _ => unreachable!(),
     }
 }
 
+fn parse_attribute(attribute: Pair<Rule>) -> KalosAttribute {
+    assert!(attribute.as_rule() == Rule::inner_attribute);
+    let span = parse_span(attribute.as_span());
+    let mut parts = attribute.into_inner().map(parse_identifier);
+    let name = parts.next().unwrap();
+    KalosAttribute { name, args: parts.collect(), span }
+}
+
 pub fn parse_program(t: Pairs<Rule>) -> KalosProgram {
-    let program = t.take_while(|p| p.as_rule() != Rule::EOI).map(parse_toplevel).collect();
+    let mut attributes = Vec::new();
+    let mut program = Vec::new();
+    for p in t.take_while(|p| p.as_rule() != Rule::EOI) {
+        match p.as_rule() {
+            Rule::inner_attribute => attributes.push(parse_attribute(p)),
+            _ => program.push(parse_toplevel(p)),
+        }
+    }
     KalosProgram {
+        attributes,
         program,
     }
 }
+
+// so that a script with many errors does not take long to report them
+const MAX_SYNTAX_ERRORS: usize = 100;
+
+/// What `rule` is called in syntax errors.
+fn describe_rule(rule: Rule) -> &'static str {
+    use Rule::*;
+    match rule {
+        inner_attribute => "an attribute",
+        def => "a definition",
+        signature => "a signature",
+        param | param_list => "a parameter",
+        ellipsis => "`...`",
+        colon => "`:`",
+        compound_stmt | var_stmt | assignment_stmt | return_stmt | if_stmt | while_stmt |
+        expr_stmt => "a statement",
+        expr | call => "an expression",
+        arg_list => "an argument",
+        add | subtract | multiply | divide | modulo | power | less_than | less_equal | equal |
+        greater_equal | greater_than | not_equal => "an operator",
+        type_expr | auto | int | boolean => "a type",
+        literal => "a number",
+        boolean_literal => "a boolean",
+        string => "a string",
+        identifier => "a name",
+        EOI => "the end of the file",
+        _ => "something else",
+    }
+}
+
+/// What `rules` are called, as a list such as "a, b or c" without repeats.
+fn describe_rules(rules: &[Rule]) -> String {
+    let mut things: Vec<&str> = Vec::new();
+    for &rule in rules {
+        let thing = describe_rule(rule);
+        if !things.contains(&thing) {
+            things.push(thing);
+        }
+    }
+    // the atoms an expression may start with go without saying
+    if things.contains(&"an expression") {
+        things.retain(|&thing| !matches!(thing, "a number" | "a boolean" | "a string" | "a name"));
+    }
+    match things.split_last() {
+        Some((last, [])) => last.to_string(),
+        Some((last, rest)) => format!("{} or {}", rest.join(", "), last),
+        None => "something else".to_owned(),
+    }
+}
+
+/// The token of `source` at `pos`, as told in syntax errors, and its length.
+fn describe_token(source: &str, pos: usize) -> (String, usize) {
+    let rest = &source[pos..];
+    let word = |c: char| c.is_alphanumeric() || c == '_';
+    match rest.chars().next() {
+        None => ("the end of the file".to_owned(), 0),
+        Some(c) if word(c) => {
+            let len = rest.find(|c| !word(c)).unwrap_or(rest.len());
+            (format!("`{}`", &rest[..len]), len)
+        }
+        Some(c) => (format!("`{}`", c), c.len_utf8()),
+    }
+}
+
+/// What went wrong in `error`, raised by parsing `source`, in words rather than rule names, and
+/// where.
+fn describe_error(source: &str, error: &Error<Rule>) -> (String, KalosSpan) {
+    let (start, end) = match error.location {
+        InputLocation::Pos(pos) => (pos, pos),
+        InputLocation::Span(span) => span,
+    };
+    let (found, len) = describe_token(source, start);
+    let message = match &error.variant {
+        ErrorVariant::ParsingError { positives, negatives } if positives.is_empty() =>
+            format!("unexpected {}", describe_rules(negatives)),
+        ErrorVariant::ParsingError { positives, .. } =>
+            format!("expected {}, found {}", describe_rules(positives), found),
+        ErrorVariant::CustomError { message } => message.to_owned(),
+    };
+    let (line, column) = Position::new(source, start).unwrap().line_col();
+    let span = KalosSpan { start, end: end.max(start + len), line: line as u32, column: column as u32 };
+    (message, span)
+}
+
+/// `error`, raised by parsing `source`, with a message in words rather than rule names.
+pub fn friendly_error(source: &str, error: Error<Rule>) -> Error<Rule> {
+    let (message, span) = describe_error(source, &error);
+    let variant = ErrorVariant::CustomError { message };
+    match error.location {
+        InputLocation::Pos(_) => Error::new_from_pos(variant, Position::new(source, span.start).unwrap()),
+        InputLocation::Span((start, end)) => Error::new_from_span(variant, Span::new(source, start, end).unwrap()),
+    }
+}
+
+/// Where the statements and definitions of `source` may end: its braces, semicolons and closing
+/// brackets outside strings and comments.
+fn find_boundaries(source: &[u8]) -> Vec<(usize, u8)> {
+    let mut boundaries = Vec::new();
+    let mut i = 0;
+    while i < source.len() {
+        match source[i] {
+            b'"' => {
+                i += 1;
+                while i < source.len() && source[i] != b'"' {
+                    i += if source[i] == b'\\' { 2 } else { 1 };
+                }
+            }
+            b'/' if source.get(i + 1) == Some(&b'*') => {
+                i += 2;
+                while i < source.len() && !source[i..].starts_with(b"*/") {
+                    i += 1;
+                }
+                i += 1;
+            }
+            c @ (b';' | b'{' | b'}' | b']') => boundaries.push((i, c)),
+            _ => (),
+        }
+        i += 1;
+    }
+    boundaries
+}
+
+/// What to skip to recover from a syntax error at `pos`: the statement or definition it is in,
+/// from the boundary before `pos` to the end of the statement, which is the next semicolon or
+/// closing brace at the same depth, or the end of the enclosing block.
+fn recovery_region(source: &[u8], pos: usize) -> (usize, usize) {
+    let boundaries = find_boundaries(source);
+    let after = boundaries.iter().position(|&(at, _)| at >= pos).unwrap_or(boundaries.len());
+    let start = after.checked_sub(1).map_or(0, |i| boundaries[i].0 + 1);
+    let mut depth = 0;
+    for &(at, c) in &boundaries[after..] {
+        match c {
+            b';' | b']' if depth == 0 => return (start, at + 1),
+            b'{' => depth += 1,
+            b'}' if depth == 0 => return (start, at),
+            b'}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return (start, at + 1);
+                }
+            }
+            _ => (),
+        }
+    }
+    (start, source.len())
+}
+
+/// Parse the script `source`, reporting all its syntax errors.  After each error, the parser
+/// skips the statement or definition it is in and carries on as if it were not there, so the
+/// program is what is left of the script, or `None` if the parser gave up on it.
+pub fn parse_source(source: &str) -> (Option<KalosProgram>, Vec<KalosDiagnostic>) {
+    // what is skipped is blanked out, keeping the newlines so that positions stay the same
+    let mut masked = source.as_bytes().to_vec();
+    let mut diagnostics = Vec::new();
+    loop {
+        let text = std::str::from_utf8(&masked).unwrap();
+        let error = match KalosParser::parse(Rule::program, text) {
+            Ok(pairs) => return (Some(parse_program(pairs)), diagnostics),
+            Err(e) => e,
+        };
+        let (message, span) = describe_error(text, &error);
+        diagnostics.push(KalosDiagnostic {
+            error: KalosError::SyntaxError(message),
+            span: Some(span),
+            notes: Vec::new(),
+            severity: KalosSeverity::Error,
+        });
+        let (mut start, mut end) = recovery_region(&masked, span.start);
+        if masked[start..end].iter().all(u8::is_ascii_whitespace) {
+            // such as a stray closing brace
+            start = span.start;
+            end = text[start..].chars().next().map_or(start, |c| start + c.len_utf8());
+        }
+        if start == end || diagnostics.len() >= MAX_SYNTAX_ERRORS {
+            return (None, diagnostics);
+        }
+        for c in &mut masked[start..end] {
+            if !c.is_ascii_whitespace() {
+                *c = b' ';
+            }
+        }
+    }
+}
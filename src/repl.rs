@@ -0,0 +1,289 @@
+use std::collections::HashSet;
+use std::io::{BufRead, stdin, stdout, Write};
+
+use inkwell::context::Context;
+use pest::error::{Error, InputLocation};
+use pest::Parser;
+
+use crate::ast::{KalosError, KalosExpr, KalosExprKind, KalosSignature, KalosSpan, KalosStmt, KalosStmtKind, KalosToplevel, KalosType};
+use crate::codegen::LLVMCodeGen;
+use crate::execution::JITExecutionEngine;
+use crate::parser::{friendly_error, KalosParser, parse_expr, parse_stmt, parse_toplevel, Rule};
+use crate::runtime::{catch_trap, KalosPolicy, KalosRuntime};
+use crate::tyck::Tycker;
+
+enum ReplEntry {
+    Toplevel(KalosToplevel),
+    Stmt(KalosStmt),
+    Expr(KalosExpr),
+}
+
+impl ReplEntry {
+    /// The global this entry (re)defines, if any.  Extern declarations define nothing.
+    fn defined_name(&self) -> Option<&str> {
+        match self {
+            ReplEntry::Toplevel(KalosToplevel::Def { name, body: Some(_), .. }) => Some(name),
+            ReplEntry::Stmt(KalosStmt { kind: KalosStmtKind::Var { name, .. }, .. }) => Some(name),
+            _ => None,
+        }
+    }
+}
+
+fn parse_entry(input: &str) -> Result<Option<ReplEntry>, Error<Rule>> {
+    let pair = KalosParser::parse(Rule::repl_entry, input)?.next().unwrap();
+    Ok(match pair.as_rule() {
+        Rule::EOI => None,
+        Rule::def => Some(ReplEntry::Toplevel(parse_toplevel(pair))),
+        Rule::expr => Some(ReplEntry::Expr(parse_expr(pair))),
+        _ => Some(ReplEntry::Stmt(parse_stmt(pair))),
+    })
+}
+
+/// Whether parsing failed only because the input ended early, so that more lines should be read.
+fn is_incomplete(input: &str, error: &Error<Rule>) -> bool {
+    let pos = match error.location {
+        InputLocation::Pos(pos) => pos,
+        InputLocation::Span((_, end)) => end,
+    };
+    pos >= input.trim_end().len()
+}
+
+/// Wrap an expression of type `ty` into a function `name` returning its value.
+fn wrap_expr(name: &str, expr: KalosExpr, ty: &KalosType) -> KalosToplevel {
+    let span = expr.span;
+    let body = if let KalosType::Unit = ty {
+        let unit = KalosExpr { kind: KalosExprKind::UnitLiteral, span };
+        KalosStmtKind::Compound(vec![
+            KalosStmt { kind: KalosStmtKind::Expression(expr), span },
+            KalosStmt { kind: KalosStmtKind::Return(unit), span },
+        ])
+    } else {
+        KalosStmtKind::Return(expr)
+    };
+    KalosToplevel::Def {
+        name: name.to_owned(),
+        signature: KalosSignature {
+            params: Vec::new(),
+            return_type: box ty.to_owned(),
+            variadic: false,
+        },
+        body: Some(KalosStmt { kind: body, span }),
+        span: KalosSpan::default(),
+        name_span: KalosSpan::default(),
+        param_spans: Vec::new(),
+    }
+}
+
+struct Repl<'ctx> {
+    context: &'ctx Context,
+    runtime: &'ctx KalosRuntime,
+    tycker: Tycker,
+    engine: JITExecutionEngine<'ctx>,
+    // the globals defined by the entries so far, as opposed to the host functions
+    definitions: HashSet<String>,
+    counter: usize,
+}
+
+impl<'ctx> Repl<'ctx> {
+    fn new(context: &'ctx Context, runtime: &'ctx KalosRuntime) -> Self {
+        let mut tycker = Tycker::with_runtime(runtime, &KalosPolicy::allow_all());
+        tycker.import_prelude();
+        Self {
+            context,
+            runtime,
+            tycker,
+            engine: JITExecutionEngine::new(context.create_module("repl"), runtime)
+                .expect("some link thing failed"),
+            definitions: HashSet::new(),
+            counter: 0,
+        }
+    }
+
+    /// Check, compile and execute an entry, returning what should be printed for it.
+    fn eval(&mut self, entry: ReplEntry) -> Result<Option<String>, KalosError> {
+        // check against a copy, so that a failing entry leaves no half-defined globals behind
+        let mut tycker = self.tycker.clone();
+        let ty = match &entry {
+            ReplEntry::Toplevel(toplevel) => { tycker.tyck_toplevel(toplevel)?; None }
+            ReplEntry::Stmt(stmt) => { tycker.tyck_global_stmt(stmt)?; None }
+            ReplEntry::Expr(expr) => Some(tycker.tyck_expr(expr)?),
+        };
+
+        self.counter += 1;
+        let name = format!("__repl_{}", self.counter);
+        let module = self.context.create_module(&name);
+        {
+            let mut codegen = LLVMCodeGen::with_runtime(self.context, &module, self.runtime);
+            codegen.import_prelude(self.runtime, &KalosPolicy::allow_all());
+            for global in &self.definitions {
+                if Some(global.as_str()) != entry.defined_name() {
+                    codegen.declare_global(global, &self.tycker.get_globals()[global]);
+                }
+            }
+            match &entry {
+                ReplEntry::Toplevel(toplevel) => { codegen.compile_toplevel(toplevel)?; }
+                ReplEntry::Stmt(stmt) => { codegen.compile_global_stmt(&name, stmt)?; }
+                ReplEntry::Expr(expr) => {
+                    let ty = ty.as_ref().unwrap();
+                    if let KalosType::Unit | KalosType::Bool | KalosType::Integer { .. } = ty {
+                        codegen.compile_toplevel(&wrap_expr(&name, expr.to_owned(), ty))?;
+                    }
+                }
+            }
+        }
+        self.engine.add_module(module)?;
+        self.tycker = tycker;
+        if let Some(name) = entry.defined_name() {
+            self.definitions.insert(name.to_owned());
+        }
+
+        Ok(match (&entry, ty) {
+            (ReplEntry::Toplevel(_), _) => None,
+            (ReplEntry::Stmt(_), _) => {
+                let func = unsafe { self.engine.get_function::<unsafe extern "C" fn()>(&name) };
+                catch_trap(|| unsafe { func.unwrap().call() })?;
+                None
+            }
+            (ReplEntry::Expr(_), Some(ty)) => Some(format!("{}: {}", self.call(&name, &ty)?, ty)),
+            _ => unreachable!(),
+        })
+    }
+
+    fn call(&self, name: &str, ty: &KalosType) -> Result<String, KalosError> {
+        catch_trap(|| unsafe {
+            match ty {
+                KalosType::Unit => {
+                    self.engine.get_function::<unsafe extern "C" fn()>(name).unwrap().call();
+                    "()".to_owned()
+                }
+                KalosType::Bool =>
+                    self.engine.get_function::<unsafe extern "C" fn() -> bool>(name).unwrap()
+                        .call().to_string(),
+                KalosType::Integer { .. } =>
+                    self.engine.get_function::<unsafe extern "C" fn() -> i64>(name).unwrap()
+                        .call().to_string(),
+                // there is nothing to evaluate, the type is all we can show
+                _ => "_".to_owned(),
+            }
+        })
+    }
+}
+
+/// Read definitions, statements and expressions from stdin, evaluating them as they come.
+pub fn repl(runtime: &KalosRuntime) {
+    let context = Context::create();
+    let mut repl = Repl::new(&context, runtime);
+
+    let stdin = stdin();
+    let mut input = String::new();
+    loop {
+        print!("{}", if input.is_empty() { ">>> " } else { "... " });
+        stdout().flush().unwrap();
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap() == 0 {
+            println!();
+            break;
+        }
+        // an empty line gives up on an unfinished entry, reporting what is wrong with it
+        let give_up = line.trim().is_empty();
+        input.push_str(&line);
+        let entry = match parse_entry(&input) {
+            Ok(entry) => entry,
+            Err(e) if !give_up && is_incomplete(&input, &e) => continue,
+            Err(e) => {
+                eprintln!("{}", friendly_error(&input, e));
+                input.clear();
+                continue;
+            }
+        };
+        input.clear();
+        if let Some(entry) = entry {
+            match repl.eval(entry) {
+                Ok(Some(output)) => println!("{}", output),
+                Ok(None) => {}
+                Err(e) => eprintln!("{}", e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::default_runtime;
+
+    fn eval(repl: &mut Repl, input: &str) -> Result<Option<String>, KalosError> {
+        repl.eval(parse_entry(input).unwrap().unwrap())
+    }
+
+    #[test]
+    fn test_def_then_call() {
+        let context = Context::create();
+        let runtime = default_runtime();
+        let mut repl = Repl::new(&context, &runtime);
+        assert_eq!(eval(&mut repl, "def square(x: int) -> int { return x * x; }").unwrap(), None);
+        assert_eq!(eval(&mut repl, "square(7)").unwrap().unwrap(), "49: i64");
+        assert_eq!(eval(&mut repl, "square(2) < 3").unwrap().unwrap(), "false: bool");
+    }
+
+    #[test]
+    fn test_globals_persist() {
+        let context = Context::create();
+        let runtime = default_runtime();
+        let mut repl = Repl::new(&context, &runtime);
+        assert_eq!(eval(&mut repl, "var total = 1;").unwrap(), None);
+        assert_eq!(eval(&mut repl, "total = total + 2;").unwrap(), None);
+        assert_eq!(eval(&mut repl, "def get() -> int { return total; }").unwrap(), None);
+        assert_eq!(eval(&mut repl, "total = total * 5;").unwrap(), None);
+        assert_eq!(eval(&mut repl, "get()").unwrap().unwrap(), "15: i64");
+        // globals of the other types
+        assert_eq!(eval(&mut repl, "var greeting = \"hello\";").unwrap(), None);
+        assert_eq!(eval(&mut repl, "var big = total > 10;").unwrap(), None);
+        assert_eq!(eval(&mut repl, "println(\"{}\", greeting)").unwrap().unwrap(), "(): ()");
+        assert_eq!(eval(&mut repl, "big").unwrap().unwrap(), "true: bool");
+    }
+
+    #[test]
+    fn test_redefinition() {
+        let context = Context::create();
+        let runtime = default_runtime();
+        let mut repl = Repl::new(&context, &runtime);
+        assert_eq!(eval(&mut repl, "def f() -> int { return 1; }").unwrap(), None);
+        assert_eq!(eval(&mut repl, "def g() -> int { return f() + 10; }").unwrap(), None);
+        assert_eq!(eval(&mut repl, "g()").unwrap().unwrap(), "11: i64");
+        // `g` has been linked against the old `f`, and calls the new one all the same
+        assert_eq!(eval(&mut repl, "def f() -> int { return 2; }").unwrap(), None);
+        assert_eq!(eval(&mut repl, "g()").unwrap().unwrap(), "12: i64");
+        assert_eq!(eval(&mut repl, "f()").unwrap().unwrap(), "2: i64");
+        // which it could not call as it was declared
+        let result = eval(&mut repl, "def f(x: bool) -> bool { return x; }");
+        assert!(matches!(result, Err(KalosError::RedefinitionError(name)) if name == "f"));
+        assert_eq!(eval(&mut repl, "g()").unwrap().unwrap(), "12: i64");
+    }
+
+    #[test]
+    fn test_incomplete() {
+        let input = "def f(x: int) -> int {\n\tif (x > 0) {\n";
+        assert!(is_incomplete(input, &parse_entry(input).err().unwrap()));
+        let input = "def f(x: int) -> int {\n\tif (x > 0) {\n\t\treturn x;\n\t}\n\treturn 0;\n}\n";
+        assert!(matches!(parse_entry(input), Ok(Some(ReplEntry::Toplevel(_)))));
+        // more input cannot make up for what is already wrong
+        let input = "def f(x: int) -> int }\n";
+        assert!(!is_incomplete(input, &parse_entry(input).err().unwrap()));
+        assert!(matches!(parse_entry("  \n"), Ok(None)));
+    }
+
+    #[test]
+    fn test_error_rolls_back() {
+        let context = Context::create();
+        let runtime = default_runtime();
+        let mut repl = Repl::new(&context, &runtime);
+        assert_eq!(eval(&mut repl, "var x = 1;").unwrap(), None);
+        // `y` is checked and found wrong after `f` has been bound
+        let result = eval(&mut repl, "def f() -> int { var y: bool = x; return x; }");
+        assert!(matches!(result, Err(KalosError::Diagnostics(_))));
+        assert!(matches!(eval(&mut repl, "f()"), Err(KalosError::Diagnostics(d))
+            if matches!(d[0].error, KalosError::NameError { .. })));
+        assert_eq!(eval(&mut repl, "x").unwrap().unwrap(), "1: i64");
+    }
+}
@@ -1,25 +1,600 @@
-use std::io::stdin;
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::ffi::{c_void, CStr, CString};
+use std::io::{self, BufRead, Read, stdin};
+use std::os::raw::c_char;
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::sync::atomic::{AtomicBool, Ordering};
 
-use lazy_static::lazy_static;
+use crate::ast::{KalosError, KalosSignature, KalosType};
 
-unsafe extern "C" fn println(n: i64, mut args: ...) {
-    let mut values = Vec::new();
-    for _ in 0..n {
-        values.push(args.arg::<i64>().to_string());
+/// Rust types that host functions can take and return; `()` can only be returned.
+pub trait KalosValue: 'static {
+    fn kalos_type() -> KalosType;
+}
+
+impl KalosValue for () {
+    fn kalos_type() -> KalosType { KalosType::Unit }
+}
+
+impl KalosValue for bool {
+    fn kalos_type() -> KalosType { KalosType::Bool }
+}
+
+impl KalosValue for i64 {
+    fn kalos_type() -> KalosType { KalosType::Integer { signed: true, width: 64 } }
+}
+
+impl KalosValue for *const c_char {
+    fn kalos_type() -> KalosType { KalosType::Text }
+}
+
+/// Function pointers that scripts can call, with the signature they must be declared with.
+pub trait KalosHostFunction: Copy {
+    fn signature() -> KalosSignature;
+    fn address(self) -> usize;
+}
+
+fn host_signature<R: KalosValue>(params: Vec<KalosType>, variadic: bool) -> KalosSignature {
+    assert!(!params.contains(&KalosType::Unit), "host functions cannot take ()");
+    KalosSignature {
+        params: params.into_iter().map(|ty| ("_".to_owned(), ty)).collect(),
+        return_type: box R::kalos_type(),
+        variadic,
+    }
+}
+
+macro_rules! impl_host_function {
+    ($($param:ident),*) => {
+        impl<R: KalosValue, $($param: KalosValue),*> KalosHostFunction for extern "C" fn($($param),*) -> R {
+            fn signature() -> KalosSignature {
+                host_signature::<R>(vec![$($param::kalos_type()),*], false)
+            }
+            fn address(self) -> usize { self as usize }
+        }
+
+        impl<R: KalosValue, $($param: KalosValue),*> KalosHostFunction for unsafe extern "C" fn($($param),*) -> R {
+            fn signature() -> KalosSignature {
+                host_signature::<R>(vec![$($param::kalos_type()),*], false)
+            }
+            fn address(self) -> usize { self as usize }
+        }
+    };
+}
+
+// C variadic functions need at least one named parameter
+macro_rules! impl_variadic_host_function {
+    ($($param:ident),+) => {
+        impl<R: KalosValue, $($param: KalosValue),+> KalosHostFunction for unsafe extern "C" fn($($param),+, ...) -> R {
+            fn signature() -> KalosSignature {
+                host_signature::<R>(vec![$($param::kalos_type()),+], true)
+            }
+            fn address(self) -> usize { self as usize }
+        }
+    };
+}
+
+impl_host_function!();
+impl_host_function!(A);
+impl_host_function!(A, B);
+impl_host_function!(A, B, C);
+impl_host_function!(A, B, C, D);
+impl_host_function!(A, B, C, D, E);
+impl_host_function!(A, B, C, D, E, F);
+impl_variadic_host_function!(A);
+impl_variadic_host_function!(A, B);
+impl_variadic_host_function!(A, B, C);
+
+/// Rust closures that scripts can call, through a trampoline taking their state first.
+pub trait KalosClosure<Args>: 'static {
+    fn signature() -> KalosSignature;
+    fn trampoline() -> usize;
+    fn into_state(self) -> Box<dyn Any>;
+}
+
+/// The address of `state`, as passed to the trampoline of a closure.
+pub fn state_address(state: &dyn Any) -> usize {
+    state as *const dyn Any as *const c_void as usize
+}
+
+macro_rules! impl_closure {
+    ($($param:ident $arg:ident),*) => {
+        impl<Func, R: KalosValue, $($param: KalosValue),*> KalosClosure<($($param,)*)> for Func
+            where Func: FnMut($($param),*) -> R + 'static
+        {
+            fn signature() -> KalosSignature {
+                host_signature::<R>(vec![$($param::kalos_type()),*], false)
+            }
+
+            fn trampoline() -> usize {
+                extern "C" fn trampoline<R: KalosValue, $($param: KalosValue),*>(
+                    data: *const c_void, $($arg: $param),*) -> R {
+                    let func = unsafe { &*(data as *const RefCell<Box<dyn FnMut($($param),*) -> R>>) };
+                    match func.try_borrow_mut() {
+                        Ok(mut func) => trap_on_panic(|| func($($arg),*)),
+                        Err(_) => trap_with(KalosError::RuntimeError(
+                            "host closure called again while it is running".to_owned())),
+                    }
+                }
+                trampoline::<R, $($param),*> as usize
+            }
+
+            fn into_state(self) -> Box<dyn Any> {
+                let func: Box<dyn FnMut($($param),*) -> R> = Box::new(self);
+                Box::new(RefCell::new(func))
+            }
+        }
+    };
+}
+
+impl_closure!();
+impl_closure!(A a);
+impl_closure!(A a, B b);
+impl_closure!(A a, B b, C c);
+impl_closure!(A a, B b, C c, D d);
+impl_closure!(A a, B b, C c, D d, E e);
+impl_closure!(A a, B b, C c, D d, E e, F f);
+
+/// The global through which scripts find the state of the closure `name`.
+pub fn data_symbol(name: &str) -> String {
+    format!("{}.data", name)
+}
+
+struct HostFunction {
+    signature: KalosSignature,
+    addr: usize,
+    // the state of a closure
+    state: Option<Box<dyn Any>>,
+}
+
+/// The host functions made available to scripts.
+#[derive(Default)]
+pub struct KalosRuntime {
+    functions: HashMap<String, HostFunction>,
+}
+
+impl KalosRuntime {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a function pointer, whose body should run in `trap_on_panic`.
+    pub fn register<F: KalosHostFunction>(&mut self, name: &str, func: F) -> &mut Self {
+        self.functions.insert(name.to_owned(), HostFunction {
+            signature: F::signature(),
+            addr: func.address(),
+            state: None,
+        });
+        self
+    }
+
+    /// Register a closure, whose state is shared by the engines using the runtime.
+    pub fn register_closure<Args, F: KalosClosure<Args>>(&mut self, name: &str, func: F) -> &mut Self {
+        self.functions.insert(name.to_owned(), HostFunction {
+            signature: F::signature(),
+            addr: F::trampoline(),
+            state: Some(func.into_state()),
+        });
+        self
+    }
+
+    /// Provide the command-line arguments of the script as `arg_count()` and `arg(i)`.
+    pub fn register_args(&mut self, args: Vec<String>) -> &mut Self {
+        let args: Vec<CString> = args.into_iter()
+            .map(|arg| CString::new(arg.replace('\0', "")).unwrap())
+            .collect();
+        let count = args.len() as i64;
+        self.register_closure("arg_count", move || count)
+            .register_closure("arg", move |i: i64| {
+                let arg = usize::try_from(i).ok().and_then(|i| args.get(i));
+                arg.unwrap_or_else(|| panic!("argument index out of range: {}", i)).as_ptr()
+            })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&KalosSignature> {
+        self.functions.get(name).map(|f| &f.signature)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=(&str, &KalosSignature, usize)> {
+        self.functions.iter().map(|(name, f)| (name.as_str(), &f.signature, f.addr))
+    }
+
+    /// The closures, with the address of their state.
+    pub fn closures(&self) -> impl Iterator<Item=(&str, usize)> {
+        self.functions.iter()
+            .filter_map(|(name, f)| f.state.as_ref().map(|state| (name.as_str(), state_address(&**state))))
+    }
+
+    /// The host functions, with their addresses.
+    pub fn symbols(&self) -> impl Iterator<Item=(String, usize)> + '_ {
+        self.functions.iter().map(|(name, f)| (name.to_owned(), f.addr))
+    }
+}
+
+pub const TRAP_SYMBOL: &str = "kalos.trap";
+pub const TRAPPED_SYMBOL: &str = "kalos.trapped";
+pub const OUT_OF_FUEL_SYMBOL: &str = "kalos.out_of_fuel";
+pub const INTERRUPTED_SYMBOL: &str = "kalos.interrupted";
+pub const STACK_OVERFLOW_SYMBOL: &str = "kalos.stack_overflow";
+pub const FUEL_SYMBOL: &str = "kalos.fuel";
+pub const DEPTH_SYMBOL: &str = "kalos.depth";
+pub const INTERRUPT_SYMBOL: &str = "kalos.interrupt";
+
+thread_local! {
+    static TRAP: RefCell<Option<KalosError>> = RefCell::new(None);
+}
+
+fn raise_trap(error: KalosError) {
+    TRAP.with(|trap| { trap.borrow_mut().get_or_insert(error); });
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.to_owned()
+    } else {
+        "host function panicked".to_owned()
+    }
+}
+
+fn trap_with<R: KalosValue>(error: KalosError) -> R {
+    raise_trap(error);
+    // never looked at, and zeroes are valid for every `KalosValue`
+    unsafe { std::mem::zeroed() }
+}
+
+/// Run `f`, the body of a host function, turning a panic into a trap.
+pub fn trap_on_panic<R: KalosValue>(f: impl FnOnce() -> R) -> R {
+    catch_unwind(AssertUnwindSafe(f))
+        .unwrap_or_else(|payload| trap_with(KalosError::RuntimeError(panic_message(payload))))
+}
+
+extern "C" fn trap(message: *const c_char) {
+    let message = unsafe { CStr::from_ptr(message) }.to_string_lossy().into_owned();
+    raise_trap(KalosError::RuntimeError(message));
+}
+
+extern "C" fn out_of_fuel() {
+    raise_trap(KalosError::OutOfFuel);
+}
+
+extern "C" fn stack_overflow() {
+    raise_trap(KalosError::StackOverflow);
+}
+
+extern "C" fn interrupted(flag: &AtomicBool) {
+    flag.store(false, Ordering::SeqCst);
+    raise_trap(KalosError::Interrupted);
+}
+
+extern "C" fn trapped() -> bool {
+    TRAP.with(|trap| trap.borrow().is_some())
+}
+
+/// The functions generated code calls to raise and check for traps, with their addresses.
+pub fn trap_symbols() -> impl Iterator<Item=(String, usize)> {
+    vec![
+        (TRAP_SYMBOL.to_owned(), trap as extern "C" fn(*const c_char) as usize),
+        (TRAPPED_SYMBOL.to_owned(), trapped as extern "C" fn() -> bool as usize),
+        (OUT_OF_FUEL_SYMBOL.to_owned(), out_of_fuel as extern "C" fn() as usize),
+        (INTERRUPTED_SYMBOL.to_owned(), interrupted as extern "C" fn(&AtomicBool) as usize),
+        (STACK_OVERFLOW_SYMBOL.to_owned(), stack_overflow as extern "C" fn() as usize),
+    ].into_iter()
+}
+
+/// Run `f`, which calls into generated code, turning a trap into an error.
+pub fn catch_trap<R>(f: impl FnOnce() -> R) -> Result<R, KalosError> {
+    TRAP.with(|trap| trap.borrow_mut().take());
+    let result = f();
+    match TRAP.with(|trap| trap.borrow_mut().take()) {
+        Some(error) => Err(error),
+        None => Ok(result),
+    }
+}
+
+/// Which of the host functions of a runtime a script may declare and call.
+#[derive(Clone, Debug, Default)]
+pub struct KalosPolicy {
+    // `None` allows everything
+    allowed: Option<HashSet<String>>,
+}
+
+impl KalosPolicy {
+    pub fn allow_all() -> Self {
+        Self::default()
+    }
+
+    pub fn allow_only<'a>(names: impl IntoIterator<Item=&'a str>) -> Self {
+        Self { allowed: Some(names.into_iter().map(|name| name.to_owned()).collect()) }
+    }
+
+    pub fn allows(&self, name: &str) -> bool {
+        self.allowed.as_ref().map_or(true, |allowed| allowed.contains(name))
+    }
+}
+
+extern "C" fn print_str(s: *const c_char) {
+    trap_on_panic(|| print!("{}", unsafe { CStr::from_ptr(s) }.to_string_lossy()))
+}
+
+extern "C" fn print_int(n: i64) {
+    trap_on_panic(|| print!("{}", n))
+}
+
+extern "C" fn print_bool(b: bool) {
+    trap_on_panic(|| print!("{}", b))
+}
+
+// stdin read a line at a time, leaving the rest to the REPL
+#[derive(Default)]
+struct StdinLines {
+    line: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for StdinLines {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.fill_buf()?.read(buf)?;
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl BufRead for StdinLines {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos == self.line.len() {
+            self.line.clear();
+            self.pos = 0;
+            stdin().lock().read_until(b'\n', &mut self.line)?;
+        }
+        Ok(&self.line[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos += amt;
+    }
+}
+
+struct Input<R> {
+    reader: R,
+    buf: String,
+    pos: usize,
+}
+
+impl<R: BufRead> Input<R> {
+    fn new(reader: R) -> Self {
+        Self { reader, buf: String::new(), pos: 0 }
+    }
+
+    fn unread(&self) -> &str {
+        &self.buf[self.pos..]
+    }
+
+    // read errors count as the end of input
+    fn next_line(&mut self) -> bool {
+        self.buf.clear();
+        self.pos = 0;
+        matches!(self.reader.read_line(&mut self.buf), Ok(n) if n > 0)
+    }
+
+    fn has_input(&mut self) -> bool {
+        loop {
+            let unread = self.unread();
+            let skipped = unread.len() - unread.trim_start().len();
+            self.pos += skipped;
+            if !self.unread().is_empty() {
+                return true;
+            }
+            if !self.next_line() {
+                return false;
+            }
+        }
+    }
+
+    fn peek_token(&mut self) -> Option<&str> {
+        if !self.has_input() {
+            return None;
+        }
+        let unread = self.unread();
+        Some(&unread[..unread.find(char::is_whitespace).unwrap_or(unread.len())])
+    }
+
+    fn read_int(&mut self) -> Result<i64, String> {
+        let token = self.peek_token().ok_or_else(|| "no integer left to read".to_owned())?;
+        let value = token.parse()
+            .map_err(|_| format!("expected an integer, found `{}`", token.escape_debug()))?;
+        let len = token.len();
+        self.pos += len;
+        Ok(value)
     }
-    println as usize;
-    println!("{}", values.join(" "));
+
+    fn has_line(&mut self) -> bool {
+        !self.unread().is_empty() || self.next_line()
+    }
+
+    fn read_line(&mut self) -> Option<String> {
+        if !self.has_line() {
+            return None;
+        }
+        let line = self.unread().trim_end_matches(&['\n', '\r'][..]).to_owned();
+        self.pos = self.buf.len();
+        Some(line)
+    }
+}
+
+thread_local! {
+    static INPUT: RefCell<Input<StdinLines>> = RefCell::new(Input::new(StdinLines::default()));
+    // the lines returned by `read_line`, which scripts may keep in variables, see `scoped_lines`
+    static LINES: RefCell<Vec<CString>> = RefCell::new(Vec::new());
+}
+
+/// Run `f`, freeing the lines the scripts read while it runs once it has returned.
+pub fn scoped_lines<R>(f: impl FnOnce() -> R) -> R {
+    let len = LINES.with(|lines| lines.borrow().len());
+    let result = f();
+    LINES.with(|lines| lines.borrow_mut().truncate(len));
+    result
 }
 
 extern "C" fn read_int() -> i64 {
-    let mut buf = String::new();
-    stdin().read_line(&mut buf).unwrap();
-    buf.trim().parse().unwrap()
+    trap_on_panic(|| INPUT.with(|input| input.borrow_mut().read_int())
+        .unwrap_or_else(|message| trap_with(KalosError::RuntimeError(message))))
+}
+
+extern "C" fn has_int() -> bool {
+    trap_on_panic(|| INPUT.with(|input| {
+        input.borrow_mut().peek_token().map_or(false, |t| t.parse::<i64>().is_ok())
+    }))
 }
 
-lazy_static! {
-    pub static ref DEFAULT_RUNTIME: [(&'static str, usize); 2] = [
-        ("println", println as usize),
-        ("read_int", read_int as usize),
-    ];
+extern "C" fn read_line() -> *const c_char {
+    trap_on_panic(|| match INPUT.with(|input| input.borrow_mut().read_line()) {
+        Some(line) => {
+            let line = CString::new(line.replace('\0', "")).unwrap();
+            let ptr = line.as_ptr();
+            LINES.with(|lines| lines.borrow_mut().push(line));
+            ptr
+        }
+        None => trap_with(KalosError::RuntimeError("no line left to read".to_owned())),
+    })
+}
+
+extern "C" fn has_line() -> bool {
+    trap_on_panic(|| INPUT.with(|input| input.borrow_mut().has_line()))
+}
+
+extern "C" fn has_input() -> bool {
+    trap_on_panic(|| INPUT.with(|input| input.borrow_mut().has_input()))
+}
+
+pub fn default_runtime() -> KalosRuntime {
+    let mut runtime = KalosRuntime::new();
+    runtime
+        .register("print_str", print_str as extern "C" fn(*const c_char))
+        .register("print_int", print_int as extern "C" fn(i64))
+        .register("print_bool", print_bool as extern "C" fn(bool))
+        .register("read_int", read_int as extern "C" fn() -> i64)
+        .register("has_int", has_int as extern "C" fn() -> bool)
+        .register("read_line", read_line as extern "C" fn() -> *const c_char)
+        .register("has_line", has_line as extern "C" fn() -> bool)
+        .register("has_input", has_input as extern "C" fn() -> bool);
+    runtime
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::mem::transmute;
+    use std::rc::Rc;
+
+    use super::*;
+
+    type Trampoline = extern "C" fn(*const c_void, i64) -> i64;
+
+    /// The trampoline and state of the only closure of `runtime`.
+    fn closure(runtime: &KalosRuntime) -> (Trampoline, *const c_void) {
+        let (name, state) = runtime.closures().next().unwrap();
+        let trampoline = runtime.iter().find(|(n, _, _)| *n == name).unwrap().2;
+        (unsafe { transmute(trampoline) }, state as *const c_void)
+    }
+
+    #[test]
+    fn test_closure_panic() {
+        let mut runtime = KalosRuntime::new();
+        runtime.register_closure("checked", |n: i64| if n < 0 { panic!("negative: {}", n) } else { n });
+        let (trampoline, state) = closure(&runtime);
+        assert_eq!(catch_trap(|| trampoline(state, 1)).unwrap(), 1);
+        assert!(matches!(catch_trap(|| trampoline(state, -1)),
+            Err(KalosError::RuntimeError(m)) if m == "negative: -1"));
+    }
+
+    #[test]
+    fn test_closure_reentry() {
+        // what the closure calls itself through, as a script function that it calls back would
+        let this: Rc<Cell<Option<(Trampoline, *const c_void)>>> = Rc::new(Cell::new(None));
+        let inner = this.clone();
+        let mut runtime = KalosRuntime::new();
+        runtime.register_closure("again", move |n: i64| match inner.get() {
+            Some((trampoline, state)) if n > 0 => trampoline(state, n - 1) + 1,
+            _ => n,
+        });
+        let (trampoline, state) = closure(&runtime);
+        this.set(Some((trampoline, state)));
+        assert_eq!(catch_trap(|| trampoline(state, 0)).unwrap(), 0);
+        assert!(matches!(catch_trap(|| trampoline(state, 1)),
+            Err(KalosError::RuntimeError(m)) if m == "host closure called again while it is running"));
+        // and it can be called again once it has returned
+        assert_eq!(catch_trap(|| trampoline(state, 0)).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_input_tokens() {
+        let mut input = Input::new(&b"  1 -2\n\n3\t4  \n  "[..]);
+        let mut values = vec![];
+        while input.has_input() {
+            values.push(input.read_int().unwrap());
+        }
+        assert_eq!(values, vec![1, -2, 3, 4]);
+        assert_eq!(input.read_int(), Err("no integer left to read".to_owned()));
+        assert!(!input.has_line());
+        assert_eq!(input.read_line(), None);
+    }
+
+    #[test]
+    fn test_input_bad_token() {
+        let mut input = Input::new(&b"12 x3 99999999999999999999\n"[..]);
+        assert_eq!(input.read_int(), Ok(12));
+        assert_eq!(input.read_int(), Err("expected an integer, found `x3`".to_owned()));
+        // which is left for the script to read otherwise
+        assert_eq!(input.peek_token(), Some("x3"));
+        assert_eq!(input.read_line(), Some("x3 99999999999999999999".to_owned()));
+        assert!(!input.has_input());
+
+        let mut input = Input::new(&b"99999999999999999999"[..]);
+        assert!(input.read_int().is_err());
+    }
+
+    #[test]
+    fn test_input_read_line() {
+        let mut input = Input::new(&b"3\nfirst line\r\n\nlast"[..]);
+        assert_eq!(input.read_int(), Ok(3));
+        // the rest of the line holding the integer
+        assert_eq!(input.read_line(), Some("".to_owned()));
+        assert_eq!(input.read_line(), Some("first line".to_owned()));
+        assert_eq!(input.read_line(), Some("".to_owned()));
+        assert!(input.has_line());
+        assert_eq!(input.read_line(), Some("last".to_owned()));
+        assert!(!input.has_line());
+        assert_eq!(input.read_line(), None);
+    }
+
+    #[test]
+    fn test_trap_on_panic() {
+        extern "C" fn checked(n: i64) -> i64 {
+            trap_on_panic(|| if n < 0 { panic!("negative: {}", n) } else { n })
+        }
+        assert_eq!(catch_trap(|| checked(1)).unwrap(), 1);
+        assert!(matches!(catch_trap(|| checked(-1)),
+            Err(KalosError::RuntimeError(m)) if m == "negative: -1"));
+    }
+
+    #[test]
+    fn test_stale_trap() {
+        // a trap raised by a host function called outside `catch_trap` is not seen by the next run
+        trap(b"stale\0".as_ptr() as *const c_char);
+        assert!(trapped());
+        assert_eq!(catch_trap(|| 1).unwrap(), 1);
+        assert!(!trapped());
+    }
+
+    #[test]
+    fn test_scoped_lines() {
+        let line = |s: &str| LINES.with(|lines| lines.borrow_mut().push(CString::new(s).unwrap()));
+        line("kept");
+        scoped_lines(|| { line("freed"); line("freed too"); });
+        LINES.with(|lines| assert_eq!(*lines.borrow(), vec![CString::new("kept").unwrap()]));
+    }
 }
@@ -1,18 +1,163 @@
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::mem::replace;
 
-use crate::ast::{KalosBuiltin, KalosError, KalosExpr, KalosProgram, KalosStmt, KalosToplevel, KalosType::{self, *}};
-use crate::env::Env;
+use crate::ast::{KalosBuiltin, KalosDiagnostic, KalosError, KalosExpr, KalosExprKind, KalosFormatPiece, KalosProgram, KalosSeverity, KalosSignature, KalosSpan, KalosStmt, KalosStmtKind, KalosToplevel, KalosType::{self, *}};
+use crate::env::{Env, similar_names};
+use crate::lint::KalosLevel;
+use crate::parser::parse_format;
+use crate::runtime::{KalosPolicy, KalosRuntime};
 
+/// Functions that are handled by the compiler rather than defined anywhere.
+const BUILTIN_FUNCTIONS: &[&str] = &[
+    "print", "println", "panic", "abort",
+    "wrapping_add", "wrapping_sub", "wrapping_mul",
+    "checked_add", "checked_sub", "checked_mul",
+    "saturating_add", "saturating_sub", "saturating_mul",
+];
+
+/// The `wrapping_*`, `checked_*` and `saturating_*` builtins: what `name` does on overflow, which
+/// is one of these three, and the operation.
+pub fn explicit_arithmetic(name: &str) -> Option<(&str, KalosBuiltin)> {
+    let (mode, op) = name.split_at(name.find('_')?);
+    let builtin = match op {
+        "_add" => KalosBuiltin::Add,
+        "_sub" => KalosBuiltin::Subtract,
+        "_mul" => KalosBuiltin::Multiply,
+        _ => return None,
+    };
+    match mode {
+        "wrapping" | "checked" | "saturating" => Some((mode, builtin)),
+        _ => None,
+    }
+}
+
+/// The name of the builtin function `func` refers to, if any.  User-defined functions of the same
+/// name shadow builtins.
+pub fn builtin_function<'a, V>(func: &'a KalosExpr, env: &Env<String, V>) -> Option<&'a str> {
+    match &func.kind {
+        KalosExprKind::Identifier(name) if BUILTIN_FUNCTIONS.contains(&name.as_str()) &&
+            env.get(name).is_none() => Some(name),
+        _ => None,
+    }
+}
+
+/// Check that `main` is defined, taking nothing and returning nothing or its exit status.
+pub fn tyck_main(program: &KalosProgram) -> Result<(), KalosError> {
+    let (main, span) = program.program.iter().find_map(|t| match t {
+        KalosToplevel::Def { name, signature, body: Some(_), name_span, .. } if name == "main" =>
+            Some((signature, *name_span)),
+        _ => None,
+    }).ok_or_else(|| KalosError::MainError("main is not defined".to_owned()))?;
+    match *main.return_type {
+        Unit | Integer { .. } if main.params.is_empty() && !main.variadic => Ok(()),
+        _ => Err(KalosError::MainError(format!(
+            "main must be fn () -> () or fn () -> i64, found {}",
+            Function { signature: main.to_owned() })).at(span)),
+    }
+}
+
+/// A name as it is bound in some part of a script.
+#[derive(Clone, Debug)]
+pub struct KalosBinding {
+    pub name: String,
+    pub ty: KalosType,
+    /// Where it is defined, unless the host provides it.
+    pub definition: Option<KalosSpan>,
+    /// The byte range of the source where the name refers to it.
+    pub scope: (usize, usize),
+}
+
+/// A use of a name, and what it refers to.
+#[derive(Clone, Debug)]
+pub struct KalosReference {
+    pub span: KalosSpan,
+    pub name: String,
+    pub ty: KalosType,
+    pub definition: Option<KalosSpan>,
+}
+
+/// The names a script binds and uses, as recorded by a `Tycker`.
+#[derive(Clone, Debug, Default)]
+pub struct KalosIndex {
+    pub bindings: Vec<KalosBinding>,
+    pub references: Vec<KalosReference>,
+}
+
+impl KalosIndex {
+    /// The use of a name at `offset`, if any.
+    pub fn reference_at(&self, offset: usize) -> Option<&KalosReference> {
+        self.references.iter().find(|r| r.span.start <= offset && offset <= r.span.end)
+    }
+
+    /// The names in scope at `offset`, the innermost first.
+    pub fn bindings_at(&self, offset: usize) -> Vec<&KalosBinding> {
+        let mut bindings: Vec<&KalosBinding> = self.bindings.iter()
+            .filter(|b| b.scope.0 <= offset && offset < b.scope.1)
+            .collect();
+        bindings.sort_by(|x, y| y.scope.0.cmp(&x.scope.0).then_with(|| x.name.cmp(&y.name)));
+        let mut seen = HashSet::new();
+        bindings.retain(|b| seen.insert(&b.name));
+        bindings
+    }
+}
+
+#[derive(Clone)]
 pub struct Tycker {
     env: Env<String, KalosType>,
+    // where the names in `env` are defined
+    definitions: Env<String, KalosSpan>,
     current_fn_return_type: Option<KalosType>,
+    // signatures of the host functions that extern declarations are checked against, and those
+    // the host provides but the script may not use
+    host_functions: HashMap<String, KalosSignature>,
+    denied: HashSet<String>,
+    // functions declared but neither defined so far nor provided by the host
+    undefined: HashMap<String, KalosSpan>,
+    // where the variables declared without a value that may not have been assigned one so far are
+    unassigned: HashSet<KalosSpan>,
+    // the host functions and builtins that the script defines functions of the same name as, and
+    // where, see `tyck_program`
+    shadowed: HashMap<String, KalosSpan>,
+    // the errors found so far, see `take_errors`
+    errors: RefCell<Vec<KalosDiagnostic>>,
+    index: Option<RefCell<KalosIndex>>,
 }
 
 impl Tycker {
     pub fn new() -> Self {
         Self {
             env: Env::from(vec![HashMap::new()]),
+            definitions: Env::from(vec![HashMap::new()]),
             current_fn_return_type: None,
+            host_functions: HashMap::new(),
+            denied: HashSet::new(),
+            undefined: HashMap::new(),
+            unassigned: HashSet::new(),
+            shadowed: HashMap::new(),
+            errors: RefCell::new(Vec::new()),
+            index: None,
+        }
+    }
+
+    /// A `Tycker` for scripts using the host functions of `runtime` that `policy` allows.
+    pub fn with_runtime(runtime: &KalosRuntime, policy: &KalosPolicy) -> Self {
+        let (allowed, denied): (Vec<_>, Vec<_>) = runtime.iter()
+            .partition(|(name, _, _)| policy.allows(name));
+        Self {
+            host_functions: allowed.into_iter()
+                .map(|(name, signature, _)| (name.to_owned(), signature.to_owned()))
+                .collect(),
+            denied: denied.into_iter().map(|(name, _, _)| name.to_owned()).collect(),
+            ..Self::new()
+        }
+    }
+
+    /// Make the host functions the script may use available without `extern` declarations.
+    /// Definitions in the script shadow them, see `tyck_program`.
+    pub fn import_prelude(&mut self) {
+        for (name, signature) in &self.host_functions {
+            self.env.put(name.to_owned(), Function { signature: signature.to_owned() });
         }
     }
 
@@ -20,112 +165,409 @@ impl Tycker {
         self.env.tables.first().unwrap()
     }
 
-    fn tyck_builtin(&self, builtin: KalosBuiltin,
-                    args: &[KalosExpr]) -> Result<KalosType, KalosError> {
+    /// Record the names bound and used by what is checked from now on, see `take_index`.
+    pub fn record_index(&mut self) {
+        self.index = Some(RefCell::new(KalosIndex::default()));
+    }
+
+    /// What has been recorded since `record_index`, including the globals bound so far.
+    pub fn take_index(&mut self) -> KalosIndex {
+        let mut index = self.index.take().map(RefCell::into_inner).unwrap_or_default();
+        for (name, ty) in self.get_globals() {
+            index.bindings.push(KalosBinding {
+                name: name.to_owned(),
+                ty: ty.to_owned(),
+                definition: self.definitions.tables[0].get(name).copied(),
+                scope: (0, usize::MAX),
+            });
+        }
+        index
+    }
+
+    /// Record `error`, found at `span`, and carry on checking.
+    fn report(&self, span: KalosSpan, error: KalosError) {
+        self.report_with_notes(span, error, Vec::new());
+    }
+
+    fn report_with_notes(&self, span: KalosSpan, error: KalosError, notes: Vec<(KalosSpan, String)>) {
+        self.errors.borrow_mut().push(KalosDiagnostic { error, span: Some(span), notes, severity: KalosSeverity::Error });
+    }
+
+    /// The errors found since the last time, in the order they appear in the source.
+    fn take_errors(&self) -> Result<(), KalosError> {
+        let mut diagnostics = self.errors.take();
+        if diagnostics.is_empty() {
+            return Ok(());
+        }
+        diagnostics.sort_by_key(|d| d.span.map(|span| span.start));
+        Err(KalosError::Diagnostics(diagnostics))
+    }
+
+    fn bind(&mut self, name: &str, ty: KalosType, definition: KalosSpan) {
+        self.env.put(name.to_owned(), ty);
+        self.definitions.put(name.to_owned(), definition);
+    }
+
+    /// Leave the innermost scope, which is the byte range from `start` to `end`.
+    fn pop_scope(&mut self, start: usize, end: usize) {
+        let types = self.env.pop();
+        let definitions = self.definitions.pop();
+        if let Some(index) = &self.index {
+            index.borrow_mut().bindings.extend(types.into_iter().map(|(name, ty)| {
+                let definition = definitions.get(&name).copied();
+                let start = definition.map_or(start, |d| d.start.max(start));
+                KalosBinding { name, ty, definition, scope: (start, end) }
+            }));
+        }
+    }
+
+    /// The error for using `name`, which is not in scope, suggesting names that are.
+    fn name_error(&self, name: &str) -> KalosError {
+        let candidates = self.env.names().chain(BUILTIN_FUNCTIONS.iter().copied());
+        KalosError::NameError { name: name.to_owned(), suggestions: similar_names(name, candidates) }
+    }
+
+    fn check_builtin(&self, builtin: KalosBuiltin, args: &[KalosExpr]) -> KalosType {
         use KalosBuiltin::*;
-        let lhs = self.tyck_expr(&args[0])?;
-        let rhs = self.tyck_expr(&args[1])?;
+        let lhs = self.check_expr(&args[0]);
+        let rhs = self.check_expr(&args[1]);
         match builtin {
-            Add => Ok(lhs),
-            Subtract => Ok(lhs),
-            Multiply => Ok(lhs),
-            Divide => Ok(lhs),
-            Modulo => Ok(lhs),
-            Power => Ok(lhs),
-            LessThan => Ok(Bool),
-            LessEqual => Ok(Bool),
-            Equal => Ok(Bool),
-            GreaterEqual => Ok(Bool),
-            GreaterThan => Ok(Bool),
-            NotEqual => Ok(Bool),
+            Add => lhs,
+            Subtract => lhs,
+            Multiply => lhs,
+            Divide => lhs,
+            Modulo => lhs,
+            Power => lhs,
+            LessThan => Bool,
+            LessEqual => Bool,
+            Equal => Bool,
+            GreaterEqual => Bool,
+            GreaterThan => Bool,
+            NotEqual => Bool,
         }
     }
 
-    pub fn tyck_expr(&self, expr: &KalosExpr) -> Result<KalosType, KalosError> {
-        use KalosExpr::*;
-        match expr {
+    /// Check the arguments of the `print` and `println` builtins, which take a format string
+    /// literal followed by one argument for each `{}` in it.  `println` also prints a newline.
+    fn check_print(&self, args: &[KalosExpr], newline: bool) -> Result<KalosType, KalosError> {
+        let types: Vec<KalosType> = args.iter().map(|arg| self.check_expr(arg)).collect();
+        let format = match args.first().map(|arg| &arg.kind) {
+            Some(KalosExprKind::StringLiteral(format)) => format,
+            _ => return Err(KalosError::FormatError("the format must be a string literal".to_owned())),
+        };
+        let pieces = parse_format(format)
+            .ok_or_else(|| KalosError::FormatError("unmatched brace in format".to_owned()))?;
+        let n = pieces.iter().filter(|p| **p == KalosFormatPiece::Placeholder).count();
+        if n != args.len() - 1 {
+            return Err(KalosError::FormatError(
+                format!("{} placeholders but {} arguments", n, args.len() - 1)));
+        }
+        // the host functions that the pieces of the format are printed with, see
+        // `LLVMCodeGen::compile_print`, which the policy must allow
+        let mut printers = vec![];
+        let has_text = pieces.iter()
+            .any(|piece| matches!(piece, KalosFormatPiece::Text(s) if !s.is_empty()));
+        if newline || has_text {
+            printers.push("print_str");
+        }
+        for ty in &types[1..] {
+            match ty {
+                Bool => printers.push("print_bool"),
+                Integer { .. } => printers.push("print_int"),
+                Text => printers.push("print_str"),
+                Error => {}
+                ty => return Err(KalosError::FormatError(format!("cannot print values of type {}", ty))),
+            }
+        }
+        match printers.into_iter().find(|name| self.denied.contains(*name)) {
+            Some(name) => Err(KalosError::CapabilityError(
+                format!("printing needs {}, which is not allowed for this script", name))),
+            None => Ok(Unit),
+        }
+    }
+
+    fn check_builtin_call(&self, name: &str, args: &[KalosExpr]) -> Result<KalosType, KalosError> {
+        match name {
+            "print" => self.check_print(args, false),
+            "println" => self.check_print(args, true),
+            // `panic(message)` and `abort()` trap, see `catch_trap`
+            "panic" if args.len() == 1 => {
+                self.expect_expr(&Text, &args[0]);
+                Ok(Unit)
+            }
+            "abort" if args.is_empty() => Ok(Unit),
+            // `wrapping_add(x, y)`, `saturating_mul(x, y)` and the like
+            _ if explicit_arithmetic(name).is_some() && args.len() == 2 => {
+                let int = Integer { signed: true, width: 64 };
+                args.iter().for_each(|arg| self.expect_expr(&int, arg));
+                Ok(int)
+            }
+            _ => {
+                args.iter().for_each(|arg| { self.check_expr(arg); });
+                Err(KalosError::ArgError)
+            }
+        }
+    }
+
+    /// Where the function `func` is defined, to compare a call of it with its parameters.
+    fn definition_notes(&self, func: &KalosExpr) -> Vec<(KalosSpan, String)> {
+        match &func.kind {
+            KalosExprKind::Identifier(name) => self.definitions.get(name).into_iter()
+                .map(|span| (*span, format!("{} is defined here", name)))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn check_call(&self, func: &KalosExpr, args: &[KalosExpr],
+                  span: KalosSpan) -> Result<KalosType, KalosError> {
+        let ty = self.check_expr(func);
+        match ty {
+            Function { signature } if args.len() == signature.params.len() ||
+                (signature.variadic && args.len() > signature.params.len()) => {
+                let n = signature.params.len();
+                let notes = self.definition_notes(func);
+                for ((_, ty), arg) in signature.params.iter().zip(args) {
+                    let arg_type = self.check_expr(arg);
+                    if let Err(error) = ty.try_unify(&arg_type) {
+                        self.report_with_notes(arg.span, error, notes.clone());
+                    }
+                }
+                args[n..].iter().for_each(|arg| { self.check_expr(arg); });
+                Ok(*signature.return_type)
+            }
+            ty => {
+                args.iter().for_each(|arg| { self.check_expr(arg); });
+                match ty {
+                    Function { .. } => {
+                        let notes = self.definition_notes(func);
+                        self.report_with_notes(span, KalosError::ArgError, notes);
+                        Ok(Error)
+                    }
+                    // the callee has been reported already
+                    Error => Ok(Error),
+                    ty => Err(KalosError::TypeError { expect: Auto, found: ty }),
+                }
+            }
+        }
+    }
+
+    /// The type of `expr`, or `Error` if it does not check, in which case the error is reported.
+    fn check_expr(&self, expr: &KalosExpr) -> KalosType {
+        self.check_expr_kind(expr).unwrap_or_else(|error| {
+            self.report(expr.span, error);
+            Error
+        })
+    }
+
+    /// Check that `expr` has the type `expect`, reporting it where it does not.
+    fn expect_expr(&self, expect: &KalosType, expr: &KalosExpr) {
+        let ty = self.check_expr(expr);
+        if let Err(error) = expect.try_unify(&ty) {
+            self.report(expr.span, error);
+        }
+    }
+
+    fn check_expr_kind(&self, expr: &KalosExpr) -> Result<KalosType, KalosError> {
+        use KalosExprKind::*;
+        match &expr.kind {
             UnitLiteral => Ok(Unit),
             IntLiteral(_) => Ok(Integer { signed: true, width: 64 }),
             BoolLiteral(_) => Ok(Bool),
             StringLiteral(_) => Ok(Text),
-            Call { func, args } => {
-                let ty = self.tyck_expr(func)?;
-                if let Function { signature } = ty {
-                    let n = signature.params.len();
-                    if args.len() == n || (signature.variadic && args.len() > n) {
-                        signature.params.iter().zip(args).try_for_each(|((_, ty), arg)| {
-                            let arg_type = self.tyck_expr(arg)?;
-                            ty.try_unify(&arg_type).map(|_| ())
-                        })?;
-                        Ok(*signature.return_type)
-                    } else {
-                        Err(KalosError::ArgError)
+            Call { func, args } => match builtin_function(func, &self.env) {
+                Some(name) if !self.shadowed.contains_key(name) => self.check_builtin_call(name, args),
+                _ => self.check_call(func, args, expr.span),
+            },
+            Builtin { builtin, args } => Ok(self.check_builtin(*builtin, args)),
+            Identifier(name) => {
+                let ty = match (self.env.get(name), self.shadowed.get(name)) {
+                    (Some(ty), _) => ty.to_owned(),
+                    // used before the definition that shadows it, see `tyck_program`
+                    (None, Some(definition)) => {
+                        let note = format!("{} is defined here, shadowing the builtin or host function", name);
+                        let error = KalosError::NameError { name: name.to_owned(), suggestions: Vec::new() };
+                        self.report_with_notes(expr.span, error, vec![(*definition, note)]);
+                        return Ok(Error);
                     }
-                } else {
-                    Err(KalosError::TypeError { expect: Auto, found: ty })
+                    (None, None) => return Err(self.name_error(name)),
+                };
+                if let Some(definition) = self.definitions.get(name).filter(|d| self.unassigned.contains(d)) {
+                    self.report_with_notes(expr.span, KalosError::UninitializedError(name.to_owned()),
+                                           vec![(*definition, format!("{} is declared here without a value", name))]);
+                }
+                if let Some(index) = &self.index {
+                    index.borrow_mut().references.push(KalosReference {
+                        span: expr.span,
+                        name: name.to_owned(),
+                        ty: ty.to_owned(),
+                        definition: self.definitions.get(name).copied(),
+                    });
                 }
+                Ok(ty)
             }
-            Builtin { builtin, args } => self.tyck_builtin(*builtin, args),
-            Identifier(name) => Ok(self.env.get(name).ok_or(KalosError::NameError)?.to_owned()),
         }
     }
 
-    pub fn tyck_stmt(&mut self, stmt: &KalosStmt) -> Result<(), KalosError> {
-        use KalosStmt::*;
-        match stmt {
+    /// The type of `expr`, or all the errors in it.
+    pub fn tyck_expr(&self, expr: &KalosExpr) -> Result<KalosType, KalosError> {
+        let ty = self.check_expr(expr);
+        self.take_errors().map(|()| ty)
+    }
+
+    fn check_stmt(&mut self, stmt: &KalosStmt) {
+        if let Err(error) = self.check_stmt_kind(stmt) {
+            self.report(stmt.span, error);
+        }
+    }
+
+    fn check_stmt_kind(&mut self, stmt: &KalosStmt) -> Result<(), KalosError> {
+        use KalosStmtKind::*;
+        match &stmt.kind {
             Compound(s) => {
                 self.env.push_empty();
-                s.iter().try_for_each(|stmt| self.tyck_stmt(stmt))?;
-                self.env.pop();
+                self.definitions.push_empty();
+                s.iter().for_each(|stmt| self.check_stmt(stmt));
+                self.pop_scope(stmt.span.start, stmt.span.end);
             }
             Assignment { lhs, rhs } => {
-                let lhs_type = self.tyck_expr(lhs)?;
-                let rhs_type = self.tyck_expr(rhs)?;
+                let rhs_type = self.check_expr(rhs);
+                // assigning a variable is not reading it, and gives it a value from here on
+                if let KalosExprKind::Identifier(name) = &lhs.kind {
+                    if let Some(definition) = self.definitions.get(name) {
+                        self.unassigned.remove(definition);
+                    }
+                }
+                let lhs_type = self.check_expr(lhs);
                 lhs_type.try_unify(&rhs_type)?;
             }
             Var { name, ty, initializer } => {
-                let ty = if let Some(initializer) = initializer {
-                    let init_ty = self.tyck_expr(initializer)?;
-                    if ty.try_unify(&init_ty)? == &init_ty { init_ty } else { ty.to_owned() }
-                } else { ty.to_owned() };
-                self.env.put(name.to_owned(), ty);
+                // the variable is bound even if its initializer is wrong, so that its uses check
+                let init_ty = initializer.as_ref().map(|initializer| self.check_expr(initializer));
+                let result = match &init_ty {
+                    Some(init_ty) => ty.try_unify(init_ty).map(|_| init_ty.to_owned()),
+                    None if *ty == Auto => Err(KalosError::InferenceError(name.to_owned())),
+                    None => Ok(ty.to_owned()),
+                };
+                self.bind(name, result.as_ref().unwrap_or(ty).to_owned(), stmt.span);
+                // globals start out as zero, but locals must be assigned before they are read
+                if initializer.is_none() && self.env.tables.len() > 1 {
+                    self.unassigned.insert(stmt.span);
+                }
+                result?;
             }
             Return(expr) => {
-                let ty = self.tyck_expr(expr)?;
-                &self.current_fn_return_type.as_ref().unwrap().try_unify(&ty)?;
+                let ty = self.check_expr(expr);
+                // nothing after a return runs, so whatever follows may assume any variable assigned
+                self.unassigned.clear();
+                self.current_fn_return_type.as_ref().ok_or(KalosError::ReturnError)?.try_unify(&ty)?;
             }
             If { cond, then_part, else_part } => {
-                Bool.try_unify(&self.tyck_expr(cond)?)?;
-                self.tyck_stmt(then_part)?;
+                self.expect_expr(&Bool, cond);
+                let before = self.unassigned.clone();
+                self.check_stmt(then_part);
+                let after_then = replace(&mut self.unassigned, before);
                 if let Some(else_part) = else_part {
-                    self.tyck_stmt(else_part)?;
+                    self.check_stmt(else_part);
                 }
+                // a variable is assigned after the `if` only if it is whichever way it goes
+                self.unassigned.extend(after_then);
             }
             While { cond, body } => {
-                Bool.try_unify(&self.tyck_expr(cond)?)?;
-                self.tyck_stmt(body)?;
+                self.expect_expr(&Bool, cond);
+                let before = self.unassigned.clone();
+                self.check_stmt(body);
+                // the body may not run at all
+                self.unassigned = before;
             }
-            Expression(expr) => { self.tyck_expr(expr)?; }
+            Expression(expr) => { self.check_expr(expr); }
         }
         Ok(())
     }
 
-    pub fn tyck_toplevel(&mut self, toplevel: &KalosToplevel) -> Result<(), KalosError> {
+    /// Check a statement that is not part of any function, as entered in the REPL.  Variables
+    /// declared by it become globals.
+    pub fn tyck_global_stmt(&mut self, stmt: &KalosStmt) -> Result<(), KalosError> {
+        self.current_fn_return_type = None;
+        self.unassigned.clear();
+        self.check_stmt(stmt);
+        self.take_errors()
+    }
+
+    fn check_toplevel(&mut self, toplevel: &KalosToplevel) {
+        if let Err(error) = self.check_toplevel_kind(toplevel) {
+            self.report(toplevel.span(), error);
+        }
+    }
+
+    fn check_toplevel_kind(&mut self, toplevel: &KalosToplevel) -> Result<(), KalosError> {
         match toplevel {
-            KalosToplevel::Def { name, signature, body, .. } => {
-                self.env.put(name.to_owned(), Function { signature: signature.to_owned() });
+            KalosToplevel::Def { name, signature, body, span, name_span, param_spans } => {
+                self.bind(name, Function { signature: signature.to_owned() }, *name_span);
                 if let Some(body) = body {
+                    self.undefined.remove(name);
                     self.env.push(signature.params.iter().map(|x| x.to_owned()).collect());
+                    self.definitions.push(signature.params.iter().zip(param_spans)
+                        .map(|((name, _), span)| (name.to_owned(), *span))
+                        .collect());
                     self.current_fn_return_type = Some(*signature.return_type.to_owned());
-                    self.tyck_stmt(body)?;
-                    self.env.pop();
+                    self.unassigned.clear();
+                    self.check_stmt(body);
+                    self.pop_scope(body.span.start, span.end);
+                } else if let Some(expect) = self.host_functions.get(name) {
+                    if !expect.is_compatible(signature) {
+                        return Err(KalosError::ExternError {
+                            name: name.to_owned(),
+                            expect: Function { signature: expect.to_owned() },
+                            found: Function { signature: signature.to_owned() },
+                        });
+                    }
+                } else if self.denied.contains(name) {
+                    return Err(KalosError::CapabilityError(
+                        format!("{} is not allowed for this script", name)));
+                } else {
+                    // a forward declaration, which had better be followed by a definition
+                    self.undefined.insert(name.to_owned(), *span);
                 }
             }
         }
         Ok(())
     }
 
+    pub fn tyck_toplevel(&mut self, toplevel: &KalosToplevel) -> Result<(), KalosError> {
+        self.check_toplevel(toplevel);
+        self.take_errors()
+    }
+
+    /// Check `program`, reporting all the errors in it as `KalosError::Diagnostics`.
     pub fn tyck_program(&mut self, program: &KalosProgram) -> Result<(), KalosError> {
-        program.program.iter().try_for_each(|t| self.tyck_toplevel(t))
+        for attribute in &program.attributes {
+            let name = &attribute.name;
+            if name != "no_prelude" && KalosLevel::from_name(name).is_none() {
+                let known = ["no_prelude", "allow", "warn", "deny"];
+                let message = match similar_names(name, known.iter().copied()).first() {
+                    Some(similar) => format!("unknown attribute {}, did you mean {}?", name, similar),
+                    None => format!("unknown attribute {}", name),
+                };
+                self.report(attribute.span, KalosError::AttributeError(message));
+            }
+        }
+        // a function of the same name as a host function or builtin shadows it throughout the
+        // script, not just after its definition, so that the name means the same everywhere
+        self.shadowed = program.program.iter().filter_map(|t| match t {
+            KalosToplevel::Def { name, body: Some(_), name_span, .. }
+            if BUILTIN_FUNCTIONS.contains(&name.as_str()) || self.env.get(name).is_some() =>
+                Some((name.to_owned(), *name_span)),
+            _ => None,
+        }).collect();
+        for name in self.shadowed.keys() {
+            self.env.tables[0].remove(name);
+        }
+        program.program.iter().for_each(|t| self.check_toplevel(t));
+        for (name, span) in &self.undefined {
+            self.report(*span, KalosError::CapabilityError(
+                format!("{} is neither defined nor provided by the host", name)));
+        }
+        self.take_errors()
     }
 }
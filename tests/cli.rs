@@ -0,0 +1,152 @@
+use std::env::temp_dir;
+use std::fs::{create_dir_all, remove_dir_all};
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+use kalos::Json;
+
+/// Run `kalos` with `args`, feeding it `input`.
+fn kalos(args: &[&str], input: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_kalos"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // which it may exit without reading
+    child.stdin.take().unwrap().write_all(input.as_bytes()).ok();
+    child.wait_with_output().unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+const BAD_SCRIPT: &str = "def main() {\n\tvar x: int = 1 < 2;\n\treturn;\n}\n";
+
+#[test]
+fn test_exit_status() {
+    let output = kalos(&["run", "examples/a+b.kls"], "1 2\n");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "3\n");
+    // that of the script
+    let output = kalos(&["run", "examples/args.kls", "--", "a", "b"], "");
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(stdout(&output), "a\nb\n");
+    // statuses the OS would truncate
+    for status in ["256", "0 - 1"].iter() {
+        let source = format!("def main() -> int {{\n\treturn {};\n}}\n", status);
+        assert_eq!(kalos(&["run", "-"], &source).status.code(), Some(1));
+    }
+    // errors in the script
+    let output = kalos(&["check", "-"], BAD_SCRIPT);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stderr(&output), "-:2:2: TypeError: expect i64 found bool\n");
+    // usage errors
+    let usage_errors: [&[&str]; 4] =
+        [&[], &["frobnicate"], &["run"], &["--error-format=xml", "check", "-"]];
+    for args in usage_errors.iter() {
+        let output = kalos(args, "");
+        assert_eq!(output.status.code(), Some(2));
+        assert!(stderr(&output).contains("usage: kalos"));
+    }
+    // traps
+    let output = kalos(&["run", "examples/divide.kls"], "7 0\n");
+    assert_eq!(output.status.code(), Some(101));
+    assert_eq!(stderr(&output),
+               "examples/divide.kls: RuntimeError: attempt to divide by zero at 7:16\n");
+    let output = kalos(&["run", "examples/a+b.kls"], "1\n");
+    assert_eq!(output.status.code(), Some(101));
+    assert_eq!(stderr(&output), "examples/a+b.kls: RuntimeError: no integer left to read\n");
+}
+
+#[test]
+fn test_warnings() {
+    let source = "def main() {\n\tvar x = 1;\n\tprintln(\"hi\");\n\treturn;\n}\n";
+    let warning = "-:2:2: warning: unused_variables: variable x is never used\n";
+    let output = kalos(&["run", "-"], source);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "hi\n");
+    assert_eq!(stderr(&output), warning);
+    let output = kalos(&["emit", "ir", "-"], source);
+    assert_eq!(stderr(&output), warning);
+    let output = kalos(&["--allow=unused_variables", "run", "-"], source);
+    assert_eq!(stderr(&output), "");
+}
+
+#[test]
+fn test_stdin() {
+    let output = kalos(&["run", "-"], "def main() -> int {\n\treturn 7;\n}\n");
+    assert_eq!(output.status.code(), Some(7));
+    let output = kalos(&["fmt", "-"], "def main(){return;}");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "def main() {\n\treturn;\n}\n");
+}
+
+#[test]
+fn test_fmt_check() {
+    let output = kalos(&["fmt", "--check", "examples/unformatted.kls", "examples/hanoi.kls"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stdout(&output), "examples/unformatted.kls\n");
+    let output = kalos(&["fmt", "--check", "examples/hanoi.kls"], "");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "");
+}
+
+#[test]
+fn test_error_format_json() {
+    let output = kalos(&["--error-format=json", "check", "-"], BAD_SCRIPT);
+    assert_eq!(output.status.code(), Some(1));
+    let diagnostics: Vec<Json> = stderr(&output).lines()
+        .map(|line| Json::parse(line).unwrap())
+        .collect();
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].get("code").unwrap().as_str(), Some("TypeError"));
+}
+
+#[test]
+fn test_build() {
+    let dir = temp_dir().join(format!("kalos-build-{}", std::process::id()));
+    create_dir_all(&dir).unwrap();
+    let object = dir.join("a+b.o");
+    let executable = dir.join("a+b");
+    let output = kalos(&["build", "examples/a+b.kls", "-o", object.to_str().unwrap()], "");
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    let linked = Command::new("cc")
+        .arg(&object)
+        .arg("runtime/kalos_runtime.c")
+        .arg("-o")
+        .arg(&executable)
+        .status();
+    match linked {
+        Ok(status) => assert!(status.success()),
+        // there is nothing to link with, which is no fault of `kalos build`
+        Err(e) => {
+            eprintln!("skipping the link: cannot run cc: {}", e);
+            remove_dir_all(&dir).unwrap();
+            return;
+        }
+    }
+    let run = |input: &str| {
+        let mut child = Command::new(&executable)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+        child.wait_with_output().unwrap()
+    };
+    let output = run("40 2\n");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "42\n");
+    let output = run("40 x\n");
+    assert_eq!(output.status.code(), Some(101));
+    assert_eq!(stderr(&output), "RuntimeError: expected an integer, found `x`\n");
+    remove_dir_all(&dir).unwrap();
+}
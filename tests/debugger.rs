@@ -0,0 +1,52 @@
+// Debuggers find the code of the JIT through the GDB JIT interface, a list of object files that
+// LLVM maintains in the process.  This is a test of its own so that no other test adds objects to
+// or removes them from the list while it is walked.
+
+use std::slice;
+
+use inkwell::context::Context;
+use kalos::{compile, default_runtime, JITExecutionEngine, KalosOptions, read_source};
+
+#[allow(dead_code)]
+#[repr(C)]
+struct JitCodeEntry {
+    next: *const JitCodeEntry,
+    prev: *const JitCodeEntry,
+    symfile_addr: *const u8,
+    symfile_size: u64,
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+struct JitDescriptor {
+    version: u32,
+    action_flag: u32,
+    relevant_entry: *const JitCodeEntry,
+    first_entry: *const JitCodeEntry,
+}
+
+extern "C" {
+    static __jit_debug_descriptor: JitDescriptor;
+}
+
+#[test]
+fn test_jit_registration() {
+    let source = read_source("examples/hanoi.kls").unwrap();
+    let context = Context::create();
+    let runtime = default_runtime();
+    let module = compile(&context, "examples/hanoi.kls", &source, &runtime,
+                         &KalosOptions::default()).unwrap();
+    let engine = JITExecutionEngine::new(module, &runtime).unwrap();
+    // code is only emitted, and registered, once it is looked up
+    unsafe { engine.get_function::<unsafe extern "C" fn()>("main") }.unwrap();
+
+    let mut registered = false;
+    let mut entry = unsafe { __jit_debug_descriptor.first_entry };
+    while let Some(e) = unsafe { entry.as_ref() } {
+        let symfile = unsafe { slice::from_raw_parts(e.symfile_addr, e.symfile_size as usize) };
+        // the name of the compile unit, in the DWARF of the object
+        registered |= symfile.windows(9).any(|w| w == b"hanoi.kls");
+        entry = e.next;
+    }
+    assert!(registered);
+}
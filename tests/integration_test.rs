@@ -1,53 +1,74 @@
-#![feature(c_variadic)]
+use std::cell::{Cell, RefCell};
+use std::fs::read_dir;
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::rc::Rc;
+use std::thread;
+use std::time::Duration;
 
-use std::cell::RefCell;
-
-use lazy_static::lazy_static;
-
-use kalos::run;
+use inkwell::context::Context;
+use kalos::{analyze, catch_trap, check, compile, DEFAULT_MAX_DEPTH, default_runtime, diagnostics_json, dump_ast, dump_ast_json, format_source, JITExecutionEngine, KalosDiagnostic, KalosError, KalosLevel, KalosLimits, KalosLint, Json, KalosOptions, KalosPolicy, KalosRuntime, KalosSeverity, lint_source, parse, read_source, run, run_source, run_tests, run_with};
 use rand::Rng;
 
-thread_local! {
-    static INPUT_BUF: RefCell<Option<Vec<i64>>> = RefCell::new(None);
-    // Note that INPUT_BUF is fed to the program in reverse order
-    static OUTPUT_BUF: RefCell<Option<Vec<i64>>> = RefCell::new(None);
+/// What a script printed: the integers on their own, and everything as text.
+#[derive(Default)]
+struct Output {
+    ints: Vec<i64>,
+    text: String,
 }
 
-unsafe extern "C" fn println(n: i64, mut args: ...) {
-    for _ in 0..n {
-        let val = args.arg::<i64>();
-        OUTPUT_BUF.with(|output_buf| {
-            let mut output_buf = output_buf.borrow_mut();
-            let output_buf = output_buf.as_mut().unwrap();
-            output_buf.push(val)
+// Note that input is fed to the program in reverse order
+fn run_captured(name: &str, source: &str, input: Vec<i64>,
+                options: &KalosOptions) -> (Result<i64, KalosError>, Output) {
+    let input = Rc::new(RefCell::new(input));
+    let input_buf = input.clone();
+    let output = Rc::new(RefCell::new(Output::default()));
+    let (int_buf, str_buf, bool_buf) = (output.clone(), output.clone(), output.clone());
+    let mut runtime = KalosRuntime::new();
+    runtime
+        .register_closure("read_int", move || input.borrow_mut().pop().unwrap())
+        .register_closure("has_input", move || !input_buf.borrow().is_empty())
+        .register_closure("print_int", move |n: i64| {
+            let mut output = int_buf.borrow_mut();
+            output.ints.push(n);
+            output.text.push_str(&n.to_string());
+        })
+        .register_closure("print_str", move |s: *const c_char| {
+            let s = unsafe { CStr::from_ptr(s) }.to_str().unwrap();
+            str_buf.borrow_mut().text.push_str(s);
+        })
+        .register_closure("print_bool", move |b: bool| {
+            bool_buf.borrow_mut().text.push_str(&b.to_string());
         });
-    }
+    let result = run_source(name, source, &runtime, options, &KalosLimits::default());
+    let output = output.replace(Output::default());
+    (result, output)
 }
 
-extern "C" fn read_int() -> i64 {
-    INPUT_BUF.with(|input_buf| {
-        let mut input_buf = input_buf.borrow_mut();
-        let input_buf = input_buf.as_mut().unwrap();
-        input_buf.pop().unwrap()
-    })
+fn diagnostics<T>(result: Result<T, KalosError>) -> Vec<KalosDiagnostic> {
+    match result {
+        Err(KalosError::Diagnostics(diagnostics)) => diagnostics,
+        Err(error) => panic!("expect diagnostics, found {}", error),
+        Ok(_) => panic!("expect diagnostics"),
+    }
 }
 
-lazy_static! {
-    static ref TEST_RUNTIME: [(&'static str, usize); 2] = [
-        ("println", println as usize),
-        ("read_int", read_int as usize),
-    ];
+fn run_file(filename: &str, input: Vec<i64>,
+            options: &KalosOptions) -> (Result<i64, KalosError>, Output) {
+    run_captured(filename, &read_source(filename).unwrap(), input, options)
 }
 
 fn test_file(filename: &str, input: Vec<i64>, verify: impl FnOnce(&Vec<i64>) -> bool) {
-    INPUT_BUF.with(|input_buf| input_buf.replace(Some(input)));
-    OUTPUT_BUF.with(|output_buf| output_buf.replace(Some(Vec::new())));
-    run(filename, &*TEST_RUNTIME);
-    OUTPUT_BUF.with(|output_buf| {
-        let output_buf = output_buf.borrow();
-        let output_buf = output_buf.as_ref().unwrap();
-        assert!(verify(output_buf));
-    });
+    let (result, output) = run_file(filename, input, &KalosOptions::default());
+    result.unwrap();
+    assert!(verify(&output.ints));
+}
+
+fn test_trap(filename: &str, input: Vec<i64>, message: &str) {
+    match run_file(filename, input, &KalosOptions::default()) {
+        (Err(KalosError::RuntimeError(m)), output) => assert!(m == message && output.text.is_empty()),
+        (result, _) => panic!("expect a trap, found {:?}", result),
+    }
 }
 
 fn vec_equal<T: PartialEq>(lhs: &Vec<T>, rhs: &Vec<T>) -> bool {
@@ -84,6 +105,21 @@ fn test_chinese_remainder_theorem() {
 fn test_hanoi() {
     test_file("examples/hanoi.kls", vec![3],
               |v| vec_equal(v, &vec![1, 3, 1, 2, 3, 2, 1, 3, 2, 1, 2, 3, 1, 3]));
+    let (_, output) = run_file("examples/hanoi.kls", vec![2], &KalosOptions::default());
+    assert_eq!(output.text, "1 2\n1 3\n2 3\n");
+}
+
+#[test]
+fn test_print() {
+    // variables of every type, with the text printed between them
+    let source = concat!(
+        "def main() {\n\tvar s = \"{}\";\n\tvar b = 1 < 2;\n\tvar n: int;\n\tn = 42;\n",
+        "\tprint(\"{} {} \", s, b);\n\tprintln(\"{}{}\\t\\\"{}\\\"\", 1 > 2, n, s);\n\treturn;\n}\n",
+    );
+    let (result, output) = run_captured("print.kls", source, Vec::new(), &KalosOptions::default());
+    result.unwrap();
+    assert_eq!(output.text, "{} true false42\t\"{}\"\n");
+    assert!(vec_equal(&output.ints, &vec![42]));
 }
 
 #[test]
@@ -91,3 +127,630 @@ fn test_loop() {
     test_file("examples/loop.kls", Vec::new(),
               |v| vec_equal(v, &vec![625, 529, 441, 361, 289, 225, 169, 121, 81, 49, 25, 9, 1]));
 }
+
+#[test]
+fn test_sum_until_eof() {
+    let mut rng = rand::thread_rng();
+    let input: Vec<i64> = (0..rng.gen_range(0..10)).map(|_| rng.gen_range(-1000..1000)).collect();
+    let sum = input.iter().sum();
+    test_file("examples/sum.kls", input, |v| vec_equal(v, &vec![sum]));
+}
+
+#[test]
+fn test_traps() {
+    test_file("examples/divide.kls", vec![3, 7], |v| vec_equal(v, &vec![2]));
+    test_trap("examples/divide.kls", vec![0, 7], "attempt to divide by zero at 7:16");
+    test_trap("examples/divide.kls", vec![-1, i64::MIN], "attempt to divide with overflow at 7:16");
+    test_trap("examples/divide.kls", vec![7, 0], "nothing to divide");
+    // read_int panics once the input runs out
+    test_trap("examples/divide.kls", vec![7], "called `Option::unwrap()` on a `None` value");
+}
+
+#[test]
+fn test_fuel() {
+    let runtime = KalosRuntime::new();
+    let limits = KalosLimits { fuel: Some(1000), ..KalosLimits::default() };
+    let result = run_with("examples/forever.kls", &runtime, &KalosOptions::default(), &limits);
+    assert!(matches!(result, Err(KalosError::OutOfFuel)));
+}
+
+#[test]
+fn test_timeout() {
+    let runtime = KalosRuntime::new();
+    let limits = KalosLimits { timeout: Some(Duration::from_millis(100)), ..KalosLimits::default() };
+    let result = run_with("examples/forever.kls", &runtime, &KalosOptions::default(), &limits);
+    assert!(matches!(result, Err(KalosError::Interrupted)));
+}
+
+#[test]
+fn test_interrupt() {
+    let context = Context::create();
+    let runtime = KalosRuntime::new();
+    let source = "def main() {\n\treturn;\n}\n";
+    let module = compile(&context, "main.kls", source, &runtime, &KalosOptions::default()).unwrap();
+    let engine = JITExecutionEngine::new(module, &runtime).unwrap();
+    let run = || catch_trap(|| unsafe { engine.call_main() });
+    // an interrupt made while nothing runs stops the next run, and only that
+    engine.interrupt_handle().interrupt();
+    assert!(matches!(run(), Err(KalosError::Interrupted)));
+    assert!(run().is_ok());
+    // unless it is cleared first, as a timed run does
+    engine.interrupt_handle().interrupt();
+    let timeout = Duration::from_secs(10);
+    assert!(engine.call_with_timeout(timeout, || unsafe { engine.call_main() }).is_ok());
+}
+
+#[test]
+fn test_policy() {
+    let runtime = default_runtime();
+    let policy = KalosPolicy::allow_only(vec!["print_str", "print_int", "print_bool"]);
+    let options = KalosOptions { policy, ..KalosOptions::default() };
+    let result = run_with("examples/a+b.kls", &runtime, &options, &KalosLimits::default());
+    // both calls of read_int
+    assert!(matches!(result, Err(KalosError::Diagnostics(d))
+        if d.len() == 2 && d.iter().all(|d| matches!(d.error, KalosError::NameError { .. }))));
+    // nothing but the runtime is linked against
+    let result = run("examples/system.kls", &runtime);
+    assert!(matches!(result, Err(KalosError::Diagnostics(d))
+        if matches!(d[..], [KalosDiagnostic { error: KalosError::CapabilityError(_), .. }])));
+
+    // nor against what the policy denies, however the module was compiled
+    let context = Context::create();
+    let module = context.create_module("read");
+    module.add_function("read_int", context.i64_type().fn_type(&[], false), None);
+    let policy = KalosPolicy::allow_only(vec!["print_int"]);
+    assert!(matches!(JITExecutionEngine::with_policy(module, &runtime, &policy),
+        Err(KalosError::LinkError(name)) if name == "read_int"));
+}
+
+#[test]
+fn test_print_policy() {
+    let runtime = default_runtime();
+    let check_printing = |allowed: Vec<&str>, source: &str| {
+        let policy = KalosPolicy::allow_only(allowed);
+        let options = KalosOptions { policy, ..KalosOptions::default() };
+        let source = format!("def main() {{\n\t{}\n\treturn;\n}}\n", source);
+        match check(&source, &runtime, &options) {
+            Ok(_) => Ok(()),
+            Err(KalosError::Diagnostics(d)) => Err(d[0].error.to_string()),
+            Err(error) => panic!("unexpected error {}", error),
+        }
+    };
+    assert!(check_printing(vec!["print_str", "print_int"], r#"println("{}", 1);"#).is_ok());
+    assert_eq!(check_printing(vec!["print_str"], r#"println("{}", 1);"#).unwrap_err(),
+               "CapabilityError: printing needs print_int, which is not allowed for this script");
+    assert!(check_printing(vec!["print_int"], r#"print("{}", 1);"#).is_ok());
+    assert_eq!(check_printing(vec!["print_int"], r#"print("n = {}", 1);"#).unwrap_err(),
+               "CapabilityError: printing needs print_str, which is not allowed for this script");
+    assert_eq!(check_printing(vec!["print_str"], r#"print("{}", 1 < 2);"#).unwrap_err(),
+               "CapabilityError: printing needs print_bool, which is not allowed for this script");
+}
+
+#[test]
+fn test_extern_errors() {
+    let source = "def print_int(n: bool) extern;\n\ndef main() {\n\treturn;\n}\n";
+    let diagnostics = diagnostics(check(source, &default_runtime(), &KalosOptions::default()));
+    assert!(matches!(&diagnostics[..], [KalosDiagnostic { error: KalosError::ExternError { name, .. }, .. }]
+        if name == "print_int"));
+    assert_eq!(diagnostics[0].to_string(), concat!(
+        "1:1: ExternError: print_int is provided as fn (_: i64) -> () ",
+        "but declared as fn (n: bool) -> ()"));
+}
+
+#[test]
+#[should_panic(expected = "host functions cannot take ()")]
+fn test_unit_parameter() {
+    KalosRuntime::new().register_closure("ignore", |_: ()| ());
+}
+
+#[test]
+fn test_shadowing() {
+    let runtime = default_runtime();
+    // the definitions shadow the host function and the builtin before them too
+    let source = concat!(
+        "def main() {\n\tprint_int(1);\n\tabort();\n\treturn;\n}\n\n",
+        "def print_int(n: bool) {\n\treturn;\n}\n\ndef abort() {\n\treturn;\n}\n",
+    );
+    let diagnostics = diagnostics(check(source, &runtime, &KalosOptions::default()));
+    let errors: Vec<String> = diagnostics.iter().map(ToString::to_string).collect();
+    assert_eq!(errors, ["2:2: NameError: print_int is not defined", "3:2: NameError: abort is not defined"]);
+    assert_eq!(diagnostics[0].notes[0].0.line, 7);
+
+    assert!(matches!(check("#![no_prelud]\ndef main() {}\n", &runtime, &KalosOptions::default()),
+        Err(KalosError::Diagnostics(d))
+        if d[0].to_string() == "1:1: AttributeError: unknown attribute no_prelud, did you mean no_prelude?"));
+}
+
+#[test]
+fn test_redefined_host_function() {
+    // replacing the prelude function rather than leaving it next to a renamed definition
+    let source = "def print_int(n: bool) {\n\treturn;\n}\n\ndef main() -> int {\n\tprint_int(1 < 2);\n\treturn 7;\n}\n";
+    let result = run_source("a.kls", source, &default_runtime(), &KalosOptions::default(),
+                            &KalosLimits::default());
+    assert_eq!(result.unwrap(), 7);
+}
+
+#[test]
+fn test_stack_overflow() {
+    let runtime = KalosRuntime::new();
+    let result = run("examples/recurse.kls", &runtime);
+    assert!(matches!(result, Err(KalosError::StackOverflow)));
+    // not even main fits
+    let limits = KalosLimits { max_depth: Some(0), ..KalosLimits::default() };
+    let result = run_with("examples/forever.kls", &runtime, &KalosOptions::default(), &limits);
+    assert!(matches!(result, Err(KalosError::StackOverflow)));
+}
+
+#[test]
+fn test_default_max_depth() {
+    // as deep as the default limit allows, on a thread with the default stack size
+    let recurse = |depth: u64| thread::spawn(move || {
+        let source = format!("def down(n: int, a: int) -> int {{\n\tvar b = a + 1;\n\t\
+                              var c = b + n;\n\tif (n == 0) {{\n\t\treturn c;\n\t}}\n\t\
+                              return down(n - 1, c) - b;\n}}\n\n\
+                              def main() -> int {{\n\tdown({}, 1);\n\treturn 0;\n}}\n", depth);
+        run_source("down.kls", &source, &KalosRuntime::new(), &KalosOptions::default(),
+                   &KalosLimits::default())
+    }).join().unwrap();
+    // `main` takes a frame, and `down(n)` takes `n + 1`
+    assert_eq!(recurse(DEFAULT_MAX_DEPTH - 2).unwrap(), 0);
+    assert!(matches!(recurse(DEFAULT_MAX_DEPTH - 1), Err(KalosError::StackOverflow)));
+}
+
+#[test]
+fn test_checked_arithmetic() {
+    let checked = KalosOptions { checked_arithmetic: true, ..KalosOptions::default() };
+    let (result, _) = run_file("examples/a+b.kls", vec![1, i64::MAX], &checked);
+    match result {
+        Err(KalosError::RuntimeError(m)) => assert_eq!(m, "attempt to add with overflow at 1:41"),
+        result => panic!("expect a trap, found {:?}", result),
+    }
+
+    let mut rng = rand::thread_rng();
+    for _ in 0..10 {
+        // make overflows likely
+        let x: i64 = rng.gen::<i64>() >> rng.gen_range(0..63);
+        let y: i64 = rng.gen::<i64>() >> rng.gen_range(0..63);
+        let (result, output) = run_file("examples/saturate.kls", vec![y, x], &checked);
+        result.unwrap();
+        assert!(vec_equal(&output.ints, &vec![x.wrapping_add(y), x.wrapping_mul(y), x.saturating_add(y),
+                                         x.saturating_sub(y), x.saturating_mul(y)]));
+    }
+
+    // which the checked builtins do whatever the options
+    let source = "def main() -> int {\n\treturn checked_mul(read_int(), 2);\n}\n";
+    let options = KalosOptions::default();
+    let run_checked = |x| run_captured("double.kls", source, vec![x], &options).0;
+    assert_eq!(run_checked(21).unwrap(), 42);
+    assert!(matches!(run_checked(i64::MAX),
+        Err(KalosError::RuntimeError(m)) if m == "attempt to multiply with overflow at 2:9"));
+}
+
+#[test]
+fn test_power() {
+    let source = concat!(
+        "def main() {\n\tprintln(\"{} {} {} {}\", 2 ** 10, (0 - 3) ** 3, 0 ** 0, 7 ** 1);\n",
+        "\tprintln(\"{} {}\", 2 ** 3 ** 2, 2 ** 62);\n\tprintln(\"{}\", 3 ** 41);\n\treturn;\n}\n",
+    );
+    let (result, output) = run_captured("power.kls", source, vec![], &KalosOptions::default());
+    result.unwrap();
+    assert_eq!(output.ints, vec![1024, -27, 1, 7, 512, 1 << 62, 3i64.wrapping_pow(41)]);
+    // only the last of which overflows
+    let checked = KalosOptions { checked_arithmetic: true, ..KalosOptions::default() };
+    let (result, output) = run_captured("power.kls", source, vec![], &checked);
+    assert!(matches!(result,
+        Err(KalosError::RuntimeError(m)) if m == "attempt to multiply with overflow at 4:16"));
+    assert_eq!(output.ints, vec![1024, -27, 1, 7, 512, 1 << 62]);
+
+    let source = "def main() -> int {\n\treturn 2 ** (1 - 2);\n}\n";
+    let (result, _) = run_captured("power.kls", source, vec![], &KalosOptions::default());
+    assert!(matches!(result,
+        Err(KalosError::RuntimeError(m)) if m == "attempt to raise to a negative power at 2:9"));
+
+    // which uses `**` as well
+    let (result, output) = run_file("examples/unformatted.kls", vec![], &KalosOptions::default());
+    result.unwrap();
+    assert_eq!(output.ints, vec![2, 10]);
+}
+
+#[test]
+fn test_main_args() {
+    let mut runtime = KalosRuntime::new();
+    runtime
+        .register_args(vec!["a".to_owned(), "b".to_owned(), "c".to_owned()])
+        .register_closure("print_str", |_: *const c_char| ());
+    assert_eq!(run("examples/args.kls", &runtime).unwrap(), 3);
+}
+
+#[test]
+fn test_run_tests() {
+    let source = read_source("examples/tests.kls").unwrap();
+    let results = run_tests("examples/tests.kls", &source, &KalosRuntime::new(),
+                            &KalosOptions::default(), &KalosLimits::default()).unwrap();
+    assert_eq!(results.len(), 2);
+    assert!(results[0].0 == "test_arithmetic" && results[0].1.is_ok());
+    assert!(results[1].0 == "test_failure" &&
+        matches!(&results[1].1, Err(KalosError::RuntimeError(m)) if m == "abort"));
+}
+
+#[test]
+fn test_add_module() {
+    let context = Context::create();
+    let runtime = KalosRuntime::new();
+    let options = KalosOptions::default();
+    let answer = |value: i64| compile(&context, "answer.kls",
+                                      &format!("def answer() -> int {{\n\treturn {};\n}}\n", value),
+                                      &runtime, &options).unwrap();
+    let mut engine = JITExecutionEngine::new(answer(1), &runtime).unwrap();
+    let call = |engine: &JITExecutionEngine| unsafe {
+        engine.get_function::<unsafe extern "C" fn() -> i64>("answer").unwrap().call()
+    };
+    assert_eq!(call(&engine), 1);
+    engine.add_module(answer(2)).unwrap();
+    assert_eq!(call(&engine), 2);
+
+    // neither defined by an earlier module nor provided by the runtime
+    let module = context.create_module("nowhere");
+    module.add_function("nowhere", context.void_type().fn_type(&[], false), None);
+    assert!(matches!(engine.add_module(module), Err(KalosError::LinkError(name)) if name == "nowhere"));
+    assert_eq!(call(&engine), 2);
+
+    // modules linked already call `answer` through its slot as it was declared
+    let module = compile(&context, "answer.kls", "def answer(x: bool) -> bool {\n\treturn x;\n}\n",
+                         &runtime, &options).unwrap();
+    assert!(matches!(engine.add_module(module), Err(KalosError::RedefinitionError(name)) if name == "answer"));
+    assert_eq!(call(&engine), 2);
+}
+
+#[test]
+fn test_closure_per_engine() {
+    let counter = |count: Rc<Cell<i64>>| move || {
+        count.set(count.get() + 1);
+        count.get()
+    };
+    let shared = Rc::new(Cell::new(0));
+    let own = Rc::new(Cell::new(100));
+    let mut runtime = KalosRuntime::new();
+    runtime.register_closure("count", counter(shared.clone()));
+    let context = Context::create();
+    let source = "def main() -> int {\n\tcount();\n\treturn count();\n}\n";
+    let module = || compile(&context, "count.kls", source, &runtime, &KalosOptions::default()).unwrap();
+    let a = JITExecutionEngine::new(module(), &runtime).unwrap();
+    let mut b = JITExecutionEngine::new(module(), &runtime).unwrap();
+    b.set_closure("count", counter(own.clone())).unwrap();
+    assert_eq!(catch_trap(|| unsafe { a.call_main() }).unwrap(), 2);
+    assert_eq!(catch_trap(|| unsafe { b.call_main() }).unwrap(), 102);
+    assert_eq!(catch_trap(|| unsafe { a.call_main() }).unwrap(), 4);
+    assert_eq!((shared.get(), own.get()), (4, 102));
+
+    assert!(matches!(b.set_closure("count", |n: i64| n), Err(KalosError::ExternError { .. })));
+    assert!(matches!(b.set_closure("print_int", |_: i64| ()), Err(KalosError::LinkError(_))));
+}
+
+#[test]
+fn test_debug_info() {
+    let source = read_source("examples/hanoi.kls").unwrap();
+    let context = Context::create();
+    let module = compile(&context, "examples/hanoi.kls", &source, &default_runtime(),
+                         &KalosOptions::default()).unwrap();
+    let ir = module.print_to_string().to_string();
+    assert!(ir.contains("!DICompileUnit(") && ir.contains("producer: \"kalos\""));
+    let subprogram = |name: &str| ir.lines()
+        .find(|line| line.contains(&format!("!DISubprogram(name: \"{}\"", name)))
+        .unwrap_or_else(|| panic!("expect a subprogram for {}", name));
+    assert!(subprogram("hanoi").contains(", line: 1,"));
+    assert!(subprogram("main").contains(", line: 12,"));
+    // the first println in hanoi
+    assert!(ir.contains("!DILocation(line: 4, column: 3,"));
+}
+
+#[test]
+fn test_format() {
+    for entry in read_dir("examples").unwrap() {
+        let path = entry.unwrap().path();
+        let source = read_source(path.to_str().unwrap()).unwrap();
+        let formatted = format_source(&source).unwrap();
+        assert_eq!(parse(&formatted).unwrap(), parse(&source).unwrap());
+        assert_eq!(format_source(&formatted).unwrap(), formatted);
+        assert_eq!(formatted.matches("/*").count(), source.matches("/*").count());
+    }
+    let source = read_source("examples/hanoi.kls").unwrap();
+    assert_eq!(format_source(&source).unwrap(), source);
+    let formatted = format_source(&read_source("examples/unformatted.kls").unwrap()).unwrap();
+    assert!(formatted.contains("\tif (a - b - c == a - (b - c)) {\n"));
+    assert!(formatted.contains("\treturn 2 ** 3 ** a + (2 ** 3) ** b;\n"));
+}
+
+#[test]
+fn test_operator_round_trip() {
+    // each operator with how tightly it binds and whether it is right associative
+    let operators = [
+        ("**", 5, true), ("*", 4, false), ("/", 4, false), ("%", 4, false), ("+", 3, false),
+        ("-", 3, false), ("<", 2, false), ("<=", 2, false), (">", 2, false), (">=", 2, false),
+        ("==", 1, false), ("!=", 1, false),
+    ];
+    let program =
+        |expr: String| format!("def f(a: int, b: int, c: int) {{\n\treturn {};\n}}\n", expr);
+    for (op1, prec1, right1) in operators.iter() {
+        for (op2, prec2, _) in operators.iter() {
+            let source = program(format!("a {} b {} c", op1, op2));
+            let left = program(format!("(a {} b) {} c", op1, op2));
+            let right = program(format!("a {} (b {} c)", op1, op2));
+            let (grouped, other) = if prec1 > prec2 || (prec1 == prec2 && !right1) {
+                (left, right)
+            } else {
+                (right, left)
+            };
+            assert_eq!(parse(&source).unwrap(), parse(&grouped).unwrap(), "{}", source);
+            assert_eq!(format_source(&source).unwrap(), source);
+            // the parentheses that are not needed go, and those that are stay
+            assert_eq!(format_source(&grouped).unwrap(), source);
+            assert_eq!(format_source(&other).unwrap(), other);
+        }
+    }
+}
+
+#[test]
+fn test_dump_ast() {
+    for entry in read_dir("examples").unwrap() {
+        let program = parse(&read_source(entry.unwrap().path().to_str().unwrap()).unwrap()).unwrap();
+        assert_eq!(parse(&program.to_string()).unwrap(), program);
+    }
+    let source = "def f(x: int) -> int { return 1 - x - 2 ** 3 ** x; }";
+    assert_eq!(dump_ast(source).unwrap(), "\
+Program attributes=[]
+  program: Def @1:1 name=\"f\" signature=\"fn (x: i64) -> i64\"
+    body: Compound @1:22
+      stmts: Return @1:24
+        value: Builtin @1:31 op=\"-\"
+          args: Builtin @1:31 op=\"-\"
+            args: IntLiteral @1:31 value=1
+            args: Identifier @1:35 name=\"x\"
+          args: Builtin @1:39 op=\"**\"
+            args: IntLiteral @1:39 value=2
+            args: Builtin @1:44 op=\"**\"
+              args: IntLiteral @1:44 value=3
+              args: Identifier @1:49 name=\"x\"
+");
+    assert!(dump_ast_json(source).unwrap().starts_with(
+        "{\"kind\":\"Program\",\"attributes\":[],\"program\":[{\"kind\":\"Def\","));
+}
+
+#[test]
+fn test_analyze() {
+    let source = read_source("examples/hanoi.kls").unwrap();
+    let analysis = analyze(&source, &default_runtime(), &KalosOptions::default());
+    assert!(analysis.diagnostics.is_empty());
+    // the call in main
+    let reference = analysis.index.reference_at(source.rfind("hanoi(").unwrap()).unwrap();
+    assert_eq!(reference.ty.to_string(), "fn (n: i64, x: i64, y: i64, z: i64) -> ()");
+    assert_eq!(reference.definition.unwrap().line, 1);
+    let names: Vec<&str> = analysis.index.bindings_at(source.find("hanoi(n - 1").unwrap())
+        .iter().map(|b| b.name.as_str()).collect();
+    assert!(names.starts_with(&["n", "x", "y", "z"]) && names.contains(&"main"));
+    // parameters are defined where they are declared
+    let reference = analysis.index.reference_at(source.find("hanoi(n - 1").unwrap() + 6).unwrap();
+    let definition = reference.definition.unwrap();
+    assert_eq!((definition.line, definition.column), (1, 11));
+
+    let source = "def main() {\n\tvar x = 1;\n\tx = y;\n\treturn;\n}\n";
+    let analysis = analyze(source, &default_runtime(), &KalosOptions::default());
+    let diagnostic = &analysis.diagnostics[0];
+    assert!(matches!(diagnostic.error, KalosError::NameError { .. }));
+    let span = diagnostic.span.unwrap();
+    assert_eq!((span.line, span.column), (3, 6));
+    assert_eq!(analysis.index.references.len(), 1);
+
+    // what is left of a script with syntax errors is still analyzed
+    let source = concat!(
+        "def twice(n: int) -> int {\n\treturn n +;\n}\n\n",
+        "def main() {\n\ttwice(1);\n\treturn;\n}\n",
+    );
+    let analysis = analyze(source, &default_runtime(), &KalosOptions::default());
+    assert_eq!(analysis.diagnostics.len(), 1);
+    assert!(matches!(analysis.diagnostics[0].error, KalosError::SyntaxError(_)));
+    let reference = analysis.index.reference_at(source.find("twice(1)").unwrap()).unwrap();
+    assert_eq!(reference.ty.to_string(), "fn (n: i64) -> i64");
+    let names: Vec<&str> = analysis.index.bindings_at(source.find("n +").unwrap())
+        .iter().map(|b| b.name.as_str()).collect();
+    assert!(names.contains(&"n"));
+}
+
+#[test]
+fn test_syntax_errors() {
+    let source = concat!(
+        "def f(x: int) -> int {\n\tvar y = ;\n\tif (x > ) {\n\t\treturn 1;\n\t}\n\treturn x;\n}\n\n",
+        "def g(x int) {\n\treturn;\n}\n}\n\n",
+        "def main() {\n\tprintln(\"{}\", f(1) +);\n\treturn;\n}\n",
+    );
+    let errors: Vec<String> = diagnostics(parse(source)).iter().map(ToString::to_string).collect();
+    assert_eq!(errors, [
+        "2:10: SyntaxError: expected an expression, found `;`",
+        "3:10: SyntaxError: expected an expression, found `)`",
+        "9:9: SyntaxError: expected `:`, found `int`",
+        "12:1: SyntaxError: expected the end of the file or a definition, found `}`",
+        "15:22: SyntaxError: expected an expression, found `)`",
+    ]);
+    assert!(format!("{}", parse("def main() {}").unwrap()).starts_with("def main()"));
+}
+
+#[test]
+fn test_type_errors() {
+    let source = concat!(
+        "def f(x: int) -> bool {\n\tvar y: bool = x;\n\tif (x) {\n\t\treturn z;\n\t}\n\treturn x;\n}\n\n",
+        "def main() {\n\tf(1 < 2);\n\tvar w = g(1);\n\tw = w + 1;\n\treturn;\n}\n",
+    );
+    let errors: Vec<String> = diagnostics(check(source, &default_runtime(), &KalosOptions::default()))
+        .iter().map(ToString::to_string).collect();
+    // the uses of `w`, which could not be given a type, are not errors of their own
+    assert_eq!(errors, [
+        "2:2: TypeError: expect bool found i64",
+        "3:6: TypeError: expect bool found i64",
+        "4:10: NameError: z is not defined",
+        "6:2: TypeError: expect bool found i64",
+        "10:4: TypeError: expect i64 found bool",
+        "11:10: NameError: g is not defined",
+    ]);
+}
+
+#[test]
+fn test_format_errors() {
+    let source = concat!(
+        "def main() {\n\tvar format = \"{}\";\n\tprintln(format, 1);\n\tprintln(\"{} {}\", 1);\n",
+        "\tprint(\"{\", 1);\n\tprintln(\"{}\", main);\n\tvar x;\n\treturn;\n}\n",
+    );
+    let errors: Vec<String> = diagnostics(check(source, &default_runtime(), &KalosOptions::default()))
+        .iter().map(ToString::to_string).collect();
+    assert_eq!(errors, [
+        "3:2: FormatError: the format must be a string literal",
+        "4:2: FormatError: 2 placeholders but 1 arguments",
+        "5:2: FormatError: unmatched brace in format",
+        "6:2: FormatError: cannot print values of type fn () -> ()",
+        "7:2: InferenceError: the type of x is neither given nor inferred",
+    ]);
+}
+
+#[test]
+fn test_definite_assignment() {
+    let source = concat!(
+        "def f(c: bool) -> int {\n\tvar x: int;\n\tvar y: int;\n\tvar z: int;\n",
+        "\tif (c) {\n\t\tx = 1;\n\t\ty = 1;\n\t} else {\n\t\tx = 2;\n\t}\n",
+        "\twhile (c) {\n\t\tz = 3;\n\t}\n\treturn x + y + z;\n}\n\n",
+        "def g(c: bool) -> int {\n\tvar n: int;\n\tif (c) {\n\t\tn = 1;\n\t} else {\n\t\treturn 0;\n\t}\n",
+        "\tvar m: int;\n\tm = m + n;\n\treturn m;\n}\n",
+    );
+    let diagnostics = diagnostics(check(source, &default_runtime(), &KalosOptions::default()));
+    let errors: Vec<String> = diagnostics.iter().map(ToString::to_string).collect();
+    // `x` is assigned either way and `n` wherever `g` goes on, but `y` and `z` may not be
+    assert_eq!(errors, [
+        "14:13: UninitializedError: y may be read before it is assigned a value",
+        "14:17: UninitializedError: z may be read before it is assigned a value",
+        "25:6: UninitializedError: m may be read before it is assigned a value",
+    ]);
+    let declaration = diagnostics[0].notes[0].0;
+    assert_eq!((declaration.line, declaration.column), (3, 2));
+    assert_eq!(diagnostics[0].notes[0].1, "y is declared here without a value");
+}
+
+#[test]
+fn test_suggestions() {
+    let source = concat!(
+        "def factorial(n: int) -> int {\n\tvar result = 1;\n\twhile (n > 1) {\n",
+        "\t\treslt = result * n;\n\t\tn = n - 1;\n\t}\n\treturn result;\n}\n\n",
+        "def main() {\n\tprinln(\"{}\", fatorial(5));\n\treturn;\n}\n",
+    );
+    let errors: Vec<String> = diagnostics(check(source, &default_runtime(), &KalosOptions::default()))
+        .iter().map(ToString::to_string).collect();
+    assert_eq!(errors, [
+        "4:3: NameError: reslt is not defined, did you mean result?",
+        "11:2: NameError: prinln is not defined, did you mean println or print?",
+        "11:15: NameError: fatorial is not defined, did you mean factorial?",
+    ]);
+}
+
+#[test]
+fn test_explicit_arithmetic() {
+    // only addition, subtraction and multiplication have these
+    let source = "def main() -> int {\n\treturn wrapping_div(7, 2) + checked_add(1, 2);\n}\n";
+    let diagnostics = diagnostics(check(source, &default_runtime(), &KalosOptions::default()));
+    assert!(matches!(&diagnostics[..],
+        [KalosDiagnostic { error: KalosError::NameError { name, .. }, .. }] if name == "wrapping_div"));
+}
+
+#[test]
+fn test_error_format() {
+    let source = "def f(x: int) {\n\treturn;\n}\n\ndef main() {\n\tvar value = 1;\n\tf(valeu < 2);\n\treturn;\n}\n";
+    let error = check(source, &default_runtime(), &KalosOptions::default()).unwrap_err();
+    let output = diagnostics_json("a.kls", source, &error);
+    let objects: Vec<Json> = output.lines().map(|line| Json::parse(line).unwrap()).collect();
+    assert_eq!(objects.len(), 2);
+
+    let name_error = &objects[0];
+    assert_eq!(name_error.get("severity").unwrap().as_str(), Some("error"));
+    assert_eq!(name_error.get("code").unwrap().as_str(), Some("NameError"));
+    assert_eq!(name_error.get("message").unwrap().as_str(), Some("valeu is not defined, did you mean value?"));
+    let primary = name_error.get("primary").unwrap();
+    assert_eq!(primary.get("line").unwrap().as_i64(), Some(7));
+    assert_eq!(primary.get("column").unwrap().as_i64(), Some(4));
+    assert_eq!(primary.get("end_column").unwrap().as_i64(), Some(9));
+    let fix = match name_error.get("fixes").unwrap() {
+        Json::Array(fixes) => &fixes[0],
+        _ => panic!("expect fixes"),
+    };
+    assert_eq!(fix.get("replacement").unwrap().as_str(), Some("value"));
+
+    let type_error = &objects[1];
+    assert_eq!(type_error.get("code").unwrap().as_str(), Some("TypeError"));
+    assert_eq!(type_error.get("primary").unwrap().get("end").unwrap().as_i64(), Some(source.find(" < 2)").unwrap() as i64 + 4));
+    match type_error.get("secondary").unwrap() {
+        Json::Array(secondary) => {
+            // the name of the function rather than all of it
+            assert_eq!(secondary[0].get("line").unwrap().as_i64(), Some(1));
+            assert_eq!(secondary[0].get("column").unwrap().as_i64(), Some(5));
+            assert_eq!(secondary[0].get("end_column").unwrap().as_i64(), Some(6));
+            assert_eq!(secondary[0].get("label").unwrap().as_str(), Some("f is defined here"));
+        }
+        _ => panic!("expect secondary spans"),
+    }
+
+    let source = "def f(x: int) {\n\treturn;\n}\n\ndef main() {\n\tf();\n\treturn;\n}\n";
+    let diagnostics = diagnostics(check(source, &default_runtime(), &KalosOptions::default()));
+    assert_eq!(diagnostics[0].to_string(), "6:2: ArgError: wrong number of arguments");
+    assert_eq!(diagnostics[0].notes[0].0.column, 5);
+
+    let error = check("def f() {}\n", &default_runtime(), &KalosOptions::default()).unwrap_err();
+    let diagnostic = Json::parse(diagnostics_json("a.kls", "def f() {}\n", &error).trim_end()).unwrap();
+    assert_eq!(diagnostic.get("code").unwrap().as_str(), Some("MainError"));
+    assert_eq!(diagnostic.get("primary"), Some(&Json::Null));
+    let source = "def main(x: int) {}\n";
+    let error = check(source, &default_runtime(), &KalosOptions::default()).unwrap_err();
+    let diagnostic = Json::parse(diagnostics_json("a.kls", source, &error).trim_end()).unwrap();
+    assert_eq!(diagnostic.get("code").unwrap().as_str(), Some("MainError"));
+    assert_eq!(diagnostic.get("primary").unwrap().get("column").unwrap().as_i64(), Some(5));
+}
+
+#[test]
+fn test_lints() {
+    let source = concat!(
+        "#![allow(unused_functions)]\n",
+        "def helper(a: int, b: int) -> int {\n\tvar unused = 1;\n\tvar x = a;\n\tif (x > 0) {\n",
+        "\t\tvar x = 2;\n\t\treturn x;\n\t}\n\treturn x;\n\tx = 3;\n}\n\n",
+        "def main() {\n\twhile (1 < 2) {\n\t\treturn;\n\t}\n\treturn;\n}\n",
+    );
+    let runtime = default_runtime();
+    let warnings = lint_source(source, &runtime, &KalosOptions::default()).unwrap();
+    let messages: Vec<String> = warnings.iter().map(ToString::to_string).collect();
+    assert_eq!(messages, [
+        "2:20: warning: unused_parameters: parameter b is never used",
+        "3:2: warning: unused_variables: variable unused is never used",
+        "6:3: warning: shadowing: x shadows an earlier declaration",
+        "10:2: warning: unreachable_code: unreachable statement",
+        "14:9: warning: constant_conditions: the condition is always true",
+    ]);
+    assert_eq!(warnings[2].notes[0].0.line, 4);
+    let hanoi = read_source("examples/hanoi.kls").unwrap();
+    assert!(lint_source(&hanoi, &runtime, &KalosOptions::default()).unwrap().is_empty());
+
+    // the attributes of the script override the options
+    let mut options = KalosOptions::default();
+    options.lints.insert(KalosLint::Shadowing, KalosLevel::Deny);
+    options.lints.insert(KalosLint::UnusedFunctions, KalosLevel::Deny);
+    let diagnostics = diagnostics(lint_source(source, &runtime, &options));
+    let errors: Vec<&KalosDiagnostic> = diagnostics.iter().filter(|d| d.severity == KalosSeverity::Error).collect();
+    assert!(matches!(errors[..], [KalosDiagnostic { error: KalosError::Lint { lint: KalosLint::Shadowing, .. }, .. }]));
+    assert_eq!(diagnostics.len(), 5);
+    assert!(matches!(check("#![deny(unused_variabls)]\ndef main() {}\n", &runtime, &KalosOptions::default()),
+        Err(KalosError::Diagnostics(d)) if d[0].to_string() == "1:1: AttributeError: unknown lint unused_variabls"));
+
+    // but for `while (true)`, which is how a loop that ends by returning is written
+    let source = "def main() {\n\twhile (true) {\n\t\treturn;\n\t}\n\tif (false) {\n\t\treturn;\n\t}\n}\n";
+    let warnings = lint_source(source, &runtime, &KalosOptions::default()).unwrap();
+    let messages: Vec<String> = warnings.iter().map(ToString::to_string).collect();
+    assert_eq!(messages, ["5:6: warning: constant_conditions: the condition is always false"]);
+    let source = concat!(
+        "def main() -> int {\n\tvar n = 0;\n\twhile (true) {\n\t\tn = n + 1;\n",
+        "\t\tif (n == 5) {\n\t\t\treturn n;\n\t\t}\n\t}\n}\n",
+    );
+    assert!(lint_source(source, &runtime, &KalosOptions::default()).unwrap().is_empty());
+    assert_eq!(run_captured("loop.kls", source, vec![], &KalosOptions::default()).0.unwrap(), 5);
+}